@@ -0,0 +1,143 @@
+/// Pieces owned by a peer, one bit per piece, the high bit of the first byte being piece 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize, // Number of pieces
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BitfieldError {
+    #[error("bitfield of {actual} bytes for {pieces} pieces, expected {expected}")]
+    Length {
+        pieces: usize,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("spare bits at the end of the bitfield are set")]
+    SpareBits,
+    #[error("piece {index} is out of a bitfield of {len} pieces")]
+    OutOfRange { index: usize, len: usize },
+}
+
+impl Bitfield {
+    /// An empty bitfield for a torrent of `len` pieces
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// A bitfield as received in a `Bitfield` message, whose piece count is not known yet.
+    ///
+    /// Check it with [`Bitfield::checked`] before use.
+    pub fn from_payload(bits: Vec<u8>) -> Self {
+        let len = bits.len() * 8;
+        Self { bits, len }
+    }
+
+    /// Validate a received bitfield against the piece count of the torrent: its byte length has to
+    /// match and the spare trailing bits have to be cleared.
+    pub fn checked(mut self, pieces: usize) -> Result<Self, BitfieldError> {
+        let expected = pieces.div_ceil(8);
+        if self.bits.len() != expected {
+            return Err(BitfieldError::Length {
+                pieces,
+                expected,
+                actual: self.bits.len(),
+            });
+        }
+        if !pieces.is_multiple_of(8) && self.bits[expected - 1] & (0xff >> (pieces % 8)) != 0 {
+            return Err(BitfieldError::SpareBits);
+        }
+        self.len = pieces;
+        Ok(self)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.len
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) -> Result<(), BitfieldError> {
+        self.check(index)?;
+        self.bits[index / 8] |= 0x80 >> (index % 8);
+        Ok(())
+    }
+
+    pub fn clear(&mut self, index: usize) -> Result<(), BitfieldError> {
+        self.check(index)?;
+        self.bits[index / 8] &= !(0x80 >> (index % 8));
+        Ok(())
+    }
+
+    /// Number of pieces owned
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Indices of the pieces not owned yet
+    pub fn iter_missing(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| !self.has(index))
+    }
+
+    fn check(&self, index: usize) -> Result<(), BitfieldError> {
+        if index >= self.len {
+            return Err(BitfieldError::OutOfRange { index, len: self.len });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_clear() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0).unwrap();
+        bitfield.set(9).unwrap();
+        assert_eq!(bitfield.as_bytes(), [0x80, 0x40]);
+        assert_eq!(bitfield.count(), 2);
+        bitfield.clear(0).unwrap();
+        assert!(!bitfield.has(0) && bitfield.has(9));
+        assert!(matches!(bitfield.set(10), Err(BitfieldError::OutOfRange { index: 10, len: 10 })));
+        assert!(bitfield.clear(10).is_err());
+        assert!(!bitfield.has(10));
+    }
+
+    #[test]
+    fn iter_missing() {
+        let mut bitfield = Bitfield::new(5);
+        assert_eq!(bitfield.iter_missing().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        for index in [0, 2, 4] {
+            bitfield.set(index).unwrap();
+        }
+        assert_eq!(bitfield.iter_missing().collect::<Vec<_>>(), [1, 3]);
+        for index in [1, 3] {
+            bitfield.set(index).unwrap();
+        }
+        assert!(bitfield.is_complete());
+        assert_eq!(bitfield.iter_missing().count(), 0);
+    }
+
+    #[test]
+    fn checked() {
+        assert!(Bitfield::from_payload(vec![0xff, 0xc0]).checked(10).is_ok());
+        assert!(matches!(Bitfield::from_payload(vec![0xff, 0xe0]).checked(10), Err(BitfieldError::SpareBits)));
+        assert!(matches!(Bitfield::from_payload(vec![0xff]).checked(10), Err(BitfieldError::Length { .. })));
+        assert!(Bitfield::from_payload(vec![0xff]).checked(8).unwrap().is_complete());
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+/// Interval between two choking decisions
+pub const ROUND: Duration = Duration::from_secs(10);
+/// The optimistic unchoke moves to another peer every this many rounds, i.e. every 30 seconds
+const OPTIMISTIC_ROUNDS: usize = 3;
+/// Number of peers unchoked at once, the optimistic unchoke included
+pub const UNCHOKE_SLOTS: usize = 4;
+
+/// What the choker knows of a peer over the last round
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Candidate {
+    pub id: usize,
+    pub interested: bool,
+    /// The peer stopped sending us the blocks we requested
+    pub snubbed: bool,
+    /// Bytes received from the peer during the round
+    pub downloaded: usize,
+    /// Bytes sent to the peer during the round
+    pub uploaded: usize,
+}
+
+/// Tit-for-tat choker deciding which peers of a torrent we upload to.
+///
+/// Every round, the interested peers uploading the fastest to us are unchoked in return, snubbing
+/// peers excepted. Once the download is complete, nothing is uploaded to us anymore and the peers
+/// downloading the fastest from us are favored instead. One more slot goes to an optimistic
+/// unchoke rotating over the other interested peers, which lets new peers prove themselves.
+#[derive(Debug, Clone, Default)]
+pub struct Choker {
+    round: usize,
+    optimistic: Option<usize>,
+}
+
+impl Choker {
+    /// Decide which of `candidates` to unchoke for the next round
+    pub fn rechoke(&mut self, candidates: &[Candidate], seeding: bool) -> Vec<usize> {
+        // On rotation, the previous optimistic unchoke competes for a regular slot like the others
+        let previous = self.optimistic;
+        let rotate = self.round.is_multiple_of(OPTIMISTIC_ROUNDS)
+            || previous.is_none_or(|id| !candidates.iter().any(|c| c.id == id && c.interested));
+        if rotate {
+            self.optimistic = None;
+        }
+
+        let rate = |c: &Candidate| if seeding { c.uploaded } else { c.downloaded };
+        let mut regular: Vec<&Candidate> = candidates
+            .iter()
+            .filter(|c| c.interested && (seeding || !c.snubbed) && Some(c.id) != self.optimistic)
+            .collect();
+        regular.sort_by_key(|c| (std::cmp::Reverse(rate(c)), c.id));
+        regular.truncate(UNCHOKE_SLOTS - 1);
+        let mut unchoked: Vec<usize> = regular.iter().map(|c| c.id).collect();
+
+        if rotate {
+            self.optimistic = next_optimistic(candidates, &unchoked, previous);
+        }
+        unchoked.extend(self.optimistic);
+        self.round += 1;
+        unchoked
+    }
+}
+
+/// The interested peer following the current optimistic unchoke, in a rotation by id over the
+/// peers not unchoked otherwise
+fn next_optimistic(candidates: &[Candidate], unchoked: &[usize], previous: Option<usize>) -> Option<usize> {
+    let mut choked: Vec<usize> = candidates
+        .iter()
+        .filter(|c| c.interested && !unchoked.contains(&c.id))
+        .map(|c| c.id)
+        .collect();
+    choked.sort_unstable();
+    let after = previous.map_or(0, |id| id + 1);
+    choked.iter().find(|&&id| id >= after).or(choked.first()).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Interested peers 0 to 5, peer `i` having sent us `100 * i` bytes and got `100 * (5 - i)`
+    fn candidates() -> Vec<Candidate> {
+        (0..6)
+            .map(|id| Candidate { id, interested: true, snubbed: false, downloaded: 100 * id, uploaded: 100 * (5 - id) })
+            .collect()
+    }
+
+    #[test]
+    fn reciprocates_the_fastest_uploaders() {
+        let mut choker = Choker::default();
+        let unchoked = choker.rechoke(&candidates(), false);
+        assert_eq!(unchoked, [5, 4, 3, 0]);
+        assert_eq!(unchoked.len(), UNCHOKE_SLOTS);
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_three_rounds() {
+        let mut choker = Choker::default();
+        let optimistic: Vec<usize> = (0..12).map(|_| *choker.rechoke(&candidates(), false).last().unwrap()).collect();
+        assert_eq!(optimistic, [0, 0, 0, 1, 1, 1, 2, 2, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn optimistic_unchoke_moves_on_when_no_longer_interested() {
+        let mut choker = Choker::default();
+        let mut candidates = candidates();
+        assert_eq!(choker.rechoke(&candidates, false), [5, 4, 3, 0]);
+        candidates[0].interested = false;
+        assert_eq!(choker.rechoke(&candidates, false), [5, 4, 3, 1]);
+    }
+
+    #[test]
+    fn optimistic_unchoke_earning_a_regular_slot() {
+        let mut choker = Choker::default();
+        let mut candidates = candidates();
+        assert_eq!(choker.rechoke(&candidates, false), [5, 4, 3, 0]);
+        // The optimistic unchoke keeps its slot until the rotation, then competes with the others
+        candidates[0].downloaded = 1000;
+        assert_eq!(choker.rechoke(&candidates, false), [5, 4, 3, 0]);
+        assert_eq!(choker.rechoke(&candidates, false), [5, 4, 3, 0]);
+        assert_eq!(choker.rechoke(&candidates, false), [0, 5, 4, 1]);
+    }
+
+    #[test]
+    fn snubbing_peers_get_no_regular_slot() {
+        let mut choker = Choker::default();
+        let mut candidates = candidates();
+        candidates[5].snubbed = true;
+        candidates[4].snubbed = true;
+        assert_eq!(choker.rechoke(&candidates, false), [3, 2, 1, 0]);
+        // Nothing is downloaded when seeding, so snubbing does not matter
+        let mut choker = Choker::default();
+        assert_eq!(choker.rechoke(&candidates, true), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn seeding_favors_the_fastest_downloaders() {
+        let mut choker = Choker::default();
+        let mut candidates = candidates();
+        candidates[1].interested = false;
+        assert_eq!(choker.rechoke(&candidates, true), [0, 2, 3, 4]);
+        // Ties are broken by id
+        for candidate in &mut candidates {
+            candidate.uploaded = 10;
+        }
+        let mut choker = Choker::default();
+        assert_eq!(choker.rechoke(&candidates, true), [0, 2, 3, 4]);
+    }
+
+    #[test]
+    fn nobody_interested() {
+        let mut choker = Choker::default();
+        let candidates: Vec<Candidate> = candidates().into_iter().map(|c| Candidate { interested: false, ..c }).collect();
+        assert!(choker.rechoke(&candidates, false).is_empty());
+        assert!(choker.rechoke(&[], true).is_empty());
+    }
+}
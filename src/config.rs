@@ -0,0 +1,55 @@
+use crate::ratelimit::LimitSet;
+use crate::schedule::Window;
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Public routers of the mainline DHT
+const DHT_ROUTERS: [&str; 3] = ["router.bittorrent.com:6881", "router.utorrent.com:6881", "dht.transmissionbt.com:6881"];
+
+/// Settings read from the JSON configuration file, e.g.
+///
+/// ```json
+/// {
+///     "limits": { "global": { "upload": 1000000 }, "peer": { "download": 200000 } },
+///     "scheduled_limits": { "global": { "upload": 100000, "download": 500000 } },
+///     "schedule": [{ "days": ["mon", "tue", "wed", "thu", "fri"], "from": "09:00", "to": "18:00" }],
+///     "dht_bootstrap": ["router.bittorrent.com:6881"]
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Bandwidth caps in bytes per second, unlimited when missing
+    pub limits: LimitSet,
+    /// Alternative caps, used instead of `limits` during the windows of `schedule` or when requested
+    pub scheduled_limits: LimitSet,
+    /// Weekly windows of local time during which `scheduled_limits` apply
+    pub schedule: Vec<Window>,
+    /// DHT nodes to join the network through, as HOST:PORT, besides those known from last time
+    pub dht_bootstrap: Vec<String>,
+    /// Where the DHT node id and routing table are kept across restarts
+    pub dht_state: Option<PathBuf>,
+    /// Keep DHT nodes whose id does not match their address out of the routing table (BEP 42)
+    pub dht_enforce_node_ids: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            limits: LimitSet::default(),
+            scheduled_limits: LimitSet::default(),
+            schedule: Vec::new(),
+            dht_bootstrap: DHT_ROUTERS.map(String::from).to_vec(),
+            dht_state: std::env::var_os("HOME").map(|home| Path::new(&home).join(".rottorrent").join("dht.state")),
+            dht_enforce_node_ids: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(path).with_context(|| format!("read config file {}", path.display()))?;
+        serde_json::from_slice(&content).with_context(|| format!("parse config file {}", path.display()))
+    }
+}
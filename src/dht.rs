@@ -0,0 +1,1056 @@
+mod bloom;
+pub mod items;
+pub mod krpc;
+pub mod routing;
+mod security;
+
+use anyhow::Context;
+use bloom::Bloom;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use items::{Item, Signed};
+use krpc::{Args, Krpc, Response};
+use routing::{distance, Family, NodeId, RoutingTable, K};
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use rand::seq::SliceRandom;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of queries a lookup keeps in flight
+const ALPHA: usize = 3;
+/// Interval between rotations of the secret tokens derive from, a token staying valid for two
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Time after which an announced peer is forgotten unless announced again
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Number of peers returned by `get_peers`, to keep responses in a datagram
+const MAX_VALUES: usize = 50;
+/// Number of torrents, and of peers of each, stored for others, the least recently announced
+/// being dropped beyond
+const MAX_TORRENTS: usize = 1000;
+const MAX_TORRENT_PEERS: usize = 200;
+/// Time after which a stored item is forgotten unless put again (BEP 44)
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+/// Number of items stored for others, the oldest being dropped beyond
+const MAX_ITEMS: usize = 1000;
+/// Receive buffer, large enough for responses carrying items along with nodes of both families
+const MAX_DATAGRAM: usize = 4096;
+/// Number of info hashes given by `sample_infohashes`, and interval between changes of them
+const SAMPLES: usize = 20;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Number of `sample_infohashes` queries a crawl keeps in flight, and of nodes it queries at most
+const CRAWL_PARALLELISM: usize = 8;
+const CRAWL_NODES: usize = 1000;
+/// Number of distinct nodes which must report the same external address before we believe it
+const EXTERNAL_VOTES: usize = 3;
+
+/// A node of the mainline DHT (BEP 5), finding peers of torrents without trackers.
+///
+/// Nodes talk KRPC, bencoded queries and responses in UDP datagrams, and keep a Kademlia routing
+/// table of the nodes they hear from. Peers of a torrent are stored by the nodes whose ids are
+/// the closest to its info hash, which lookups converge to by querying ever closer nodes.
+///
+/// The IPv4 and IPv6 DHTs are joined through a socket each, with a routing table and an id of
+/// their own (BEP 32). Ids are derived from our external address as reported by other nodes
+/// (BEP 42).
+#[derive(Debug)]
+pub struct Dht {
+    sockets: [Option<UdpSocket>; 2], // By family
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    tables: [RoutingTable; 2], // By family
+    pending: HashMap<[u8; 2], Pending>, // Queries awaiting a response, by transaction id
+    next_transaction: u16,
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Announced>>, // Announced peers, by info hash
+    samples: Vec<[u8; 20]>, // Info hashes given by `sample_infohashes` until they are resampled
+    sampled: Instant,
+    items: HashMap<NodeId, (Item, Instant)>, // Items put by others, by target
+    secrets: [[u8; 20]; 2], // Current and previous secrets tokens derive from
+    rotated: Instant,
+    /// Our external addresses as reported in responses, with the nodes which reported them
+    votes: HashMap<IpAddr, HashSet<IpAddr>>,
+    /// Keep nodes whose id does not match their address out of the routing tables
+    enforce_node_ids: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Announced {
+    at: Instant,
+    seed: bool,
+}
+
+#[derive(Debug)]
+struct Pending {
+    addr: SocketAddr,
+    response: oneshot::Sender<anyhow::Result<Response>>,
+}
+
+/// Node ids and good nodes of a DHT node, saved across restarts so that it rejoins the network
+/// where it left it
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Saved {
+    id: ByteBuf,
+    nodes: ByteBuf, // Compact node infos
+    #[serde(default)]
+    id6: ByteBuf,
+    #[serde(default)]
+    nodes6: ByteBuf,
+}
+
+impl Saved {
+    /// Load the state saved at `path`, if any
+    pub async fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        match tokio::fs::read(path).await {
+            Ok(content) => Ok(Some(serde_bencode::from_bytes(&content).context("decode DHT state")?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("read DHT state"),
+        }
+    }
+
+    fn id(&self, family: Family) -> Option<NodeId> {
+        match family {
+            Family::V4 => krpc::id(&self.id),
+            Family::V6 => krpc::id(&self.id6),
+        }
+    }
+
+    pub fn nodes(&self) -> Vec<SocketAddr> {
+        let nodes = krpc::decode_nodes(&self.nodes, Family::V4).into_iter();
+        let nodes6 = krpc::decode_nodes(&self.nodes6, Family::V6).into_iter();
+        nodes.chain(nodes6).map(|(_, addr)| addr).collect()
+    }
+}
+
+/// Outcome of an iterative lookup
+#[derive(Debug, Default)]
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    /// The closest nodes which answered, with the token they gave for announces
+    closest: Vec<(NodeId, SocketAddr, Option<ByteBuf>)>,
+    /// Nodes of the other family given along, when both were wanted
+    others: HashSet<SocketAddr>,
+    /// Responses to `get` carrying an item
+    items: Vec<Response>,
+    /// Bloom filters of the seeds and peers given by the closest nodes to a scrape
+    filters: Vec<(Bloom, Bloom)>,
+}
+
+/// Estimated numbers of seeds and peers of a torrent, from `get_peers` scrapes (BEP 33)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Scrape {
+    pub seeds: f64,
+    pub peers: f64,
+}
+
+impl Dht {
+    /// Start a node answering queries on `addrs`, one per family, with the ids saved from last
+    /// time or random ones until our external address is known
+    pub async fn bind(addrs: &[SocketAddr], saved: Option<&Saved>, enforce_node_ids: bool) -> anyhow::Result<Arc<Self>> {
+        let mut sockets = [None, None];
+        for &addr in addrs {
+            let socket = &mut sockets[Family::of(&addr) as usize];
+            anyhow::ensure!(socket.is_none(), "more than one DHT address of the family of {addr}");
+            *socket = Some(bind_socket(addr).with_context(|| format!("bind DHT socket on {addr}"))?);
+        }
+        anyhow::ensure!(!addrs.is_empty(), "no address to bind the DHT on");
+        let table = |family| RoutingTable::new(saved.and_then(|saved| saved.id(family)).unwrap_or_else(rand::random));
+        let dht = Arc::new(Self {
+            sockets,
+            state: Mutex::new(State {
+                tables: Family::ALL.map(table),
+                pending: HashMap::new(),
+                next_transaction: rand::random(),
+                peers: HashMap::new(),
+                samples: Vec::new(),
+                sampled: Instant::now(),
+                items: HashMap::new(),
+                secrets: rand::random(),
+                rotated: Instant::now(),
+                votes: HashMap::new(),
+                enforce_node_ids,
+            }),
+        });
+        for family in Family::ALL {
+            if dht.sockets[family as usize].is_some() {
+                tokio::spawn(Arc::clone(&dht).receive(family));
+            }
+        }
+        Ok(dht)
+    }
+
+    /// Number of nodes in the routing tables
+    pub fn nodes(&self) -> usize {
+        self.state.lock().expect("DHT lock poisoned").tables.iter().map(RoutingTable::len).sum()
+    }
+
+    /// Families we have a socket for
+    fn families(&self) -> impl Iterator<Item = Family> + '_ {
+        Family::ALL.into_iter().filter(|&family| self.sockets[family as usize].is_some())
+    }
+
+    /// Save our ids and the good nodes of the routing tables
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let saved = {
+            let state = self.state.lock().expect("DHT lock poisoned");
+            let good = |family: Family| {
+                let nodes = state.tables[family as usize].nodes().filter(|node| node.is_good());
+                krpc::encode_nodes(nodes.map(|node| (node.id, node.addr)))
+            };
+            Saved {
+                id: ByteBuf::from(*state.tables[Family::V4 as usize].own()),
+                nodes: good(Family::V4),
+                id6: ByteBuf::from(*state.tables[Family::V6 as usize].own()),
+                nodes6: good(Family::V6),
+            }
+        };
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await.context("create DHT state directory")?;
+        }
+        let content = serde_bencode::to_bytes(&saved).context("encode DHT state")?;
+        let tmp = path.with_extension("state.tmp");
+        tokio::fs::write(&tmp, content).await.context("write DHT state")?;
+        tokio::fs::rename(&tmp, path).await.context("replace DHT state")
+    }
+
+    /// Join the DHT through the given nodes, then fill the routing tables with the nodes close
+    /// to us
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) {
+        let pings: FuturesUnordered<_> = nodes.iter().map(|&addr| self.ping(addr)).collect();
+        pings.for_each(|_| async {}).await;
+        let mut others = HashSet::new();
+        for family in self.families() {
+            let own = *self.state.lock().expect("DHT lock poisoned").tables[family as usize].own();
+            others.extend(self.lookup(family, own, "find_node", Args::default()).await.others);
+        }
+        // Nodes of a family we could not reach directly, as told by nodes of the other one
+        let pings: FuturesUnordered<_> = others.into_iter().take(K).map(|addr| self.ping(addr)).collect();
+        pings.for_each(|_| async {}).await;
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> anyhow::Result<NodeId> {
+        let response = self.query(addr, "ping", Args::default()).await?;
+        krpc::id(&response.id).context("invalid node id")
+    }
+
+    /// Find peers of a torrent and announce ourselves as one of them, listening on `port`
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16, seed: bool) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        for family in self.families() {
+            let lookup = self.lookup(family, info_hash, "get_peers", Args::default()).await;
+            let args = Args {
+                info_hash: Some(ByteBuf::from(info_hash)),
+                port: Some(port),
+                seed: seed.then_some(1),
+                ..Args::default()
+            };
+            self.store(lookup.closest, "announce_peer", &args).await;
+            peers.extend(lookup.peers);
+        }
+        peers
+    }
+
+    /// Get an immutable item
+    pub async fn get(&self, target: NodeId) -> Option<Value> {
+        for family in self.families() {
+            let lookup = self.lookup(family, target, "get", Args::default()).await;
+            let found = lookup.items.into_iter().filter_map(|response| response.v).find(|value| {
+                let item = Item { value: value.clone(), signed: None };
+                item.validate().is_ok() && item.target().is_ok_and(|t| t == target)
+            });
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    /// Get the latest version of the mutable item of the given key and salt
+    pub async fn get_mutable(&self, key: &[u8; 32], salt: &[u8]) -> Option<Item> {
+        let target = items::mutable_target(key, salt);
+        let mut latest: Option<Item> = None;
+        for family in self.families() {
+            for response in self.lookup(family, target, "get", Args::default()).await.items {
+                let (Some(value), Some(signature), Some(seq)) = (response.v, response.sig, response.seq) else {
+                    continue;
+                };
+                let Ok(signature) = signature.as_slice().try_into() else {
+                    continue;
+                };
+                let signed = Signed { key: *key, signature, seq, salt: salt.to_vec() };
+                let item = Item { value, signed: Some(signed) };
+                if item.validate().is_ok() && latest.as_ref().is_none_or(|latest| seq_of(latest) < seq) {
+                    latest = Some(item);
+                }
+            }
+        }
+        latest
+    }
+
+    /// Store an item on the nodes closest to its target, returning how many accepted it
+    pub async fn put(&self, item: &Item) -> anyhow::Result<usize> {
+        let target = item.target()?;
+        let mut args = Args {
+            v: Some(item.value.clone()),
+            ..Args::default()
+        };
+        if let Some(signed) = &item.signed {
+            args.k = Some(ByteBuf::from(signed.key));
+            args.sig = Some(ByteBuf::from(signed.signature));
+            args.seq = Some(signed.seq);
+            args.salt = (!signed.salt.is_empty()).then(|| ByteBuf::from(signed.salt.clone()));
+        }
+        let mut stored = 0;
+        for family in self.families() {
+            let lookup = self.lookup(family, target, "get", Args::default()).await;
+            stored += self.store(lookup.closest, "put", &args).await;
+        }
+        Ok(stored)
+    }
+
+    /// Estimate the numbers of seeds and peers of a torrent from the bloom filters of the nodes
+    /// storing its peers
+    pub async fn scrape(&self, info_hash: [u8; 20]) -> Scrape {
+        let (mut seeds, mut peers) = (Bloom::default(), Bloom::default());
+        for family in self.families() {
+            let args = Args { scrape: Some(1), ..Args::default() };
+            for (family_seeds, family_peers) in self.lookup(family, info_hash, "get_peers", args).await.filters {
+                seeds.union(&family_seeds);
+                peers.union(&family_peers);
+            }
+        }
+        Scrape {
+            seeds: seeds.estimate(),
+            peers: peers.estimate(),
+        }
+    }
+
+    /// Collect up to `count` info hashes of torrents stored across the DHT (BEP 51), crawling
+    /// from the nodes we know to those they return
+    pub async fn crawl(&self, count: usize) -> HashSet<[u8; 20]> {
+        let mut frontier: Vec<SocketAddr> = {
+            let state = self.state.lock().expect("DHT lock poisoned");
+            state.tables.iter().flat_map(RoutingTable::nodes).map(|node| node.addr).collect()
+        };
+        let mut queried = HashSet::new();
+        let mut found = HashSet::new();
+        let mut in_flight = FuturesUnordered::new();
+        while found.len() < count {
+            while in_flight.len() < CRAWL_PARALLELISM && queried.len() < CRAWL_NODES {
+                let Some(addr) = frontier.pop() else {
+                    break;
+                };
+                if queried.insert(addr) {
+                    // Random targets spread the crawl over the whole DHT
+                    let target: NodeId = rand::random();
+                    let args = Args { target: Some(ByteBuf::from(target)), ..Args::default() };
+                    in_flight.push(self.query(addr, "sample_infohashes", args));
+                }
+            }
+            let Some(result) = in_flight.next().await else {
+                break;
+            };
+            let Ok(response) = result else {
+                continue; // Not every node supports sampling
+            };
+            let samples = response.samples.as_deref().map_or(&[][..], Vec::as_slice);
+            found.extend(samples.chunks_exact(20).filter_map(|sample| <[u8; 20]>::try_from(sample).ok()));
+            for family in self.families() {
+                let nodes = match family {
+                    Family::V4 => &response.nodes,
+                    Family::V6 => &response.nodes6,
+                };
+                let nodes = krpc::decode_nodes(nodes.as_deref().map_or(&[][..], Vec::as_slice), family);
+                frontier.extend(nodes.into_iter().map(|(_, addr)| addr));
+            }
+        }
+        found
+    }
+
+    /// Send a query storing something to the nodes of a lookup which gave us a token, returning
+    /// how many accepted it
+    async fn store(&self, closest: Vec<(NodeId, SocketAddr, Option<ByteBuf>)>, method: &str, args: &Args) -> usize {
+        let queries: FuturesUnordered<_> = closest
+            .into_iter()
+            .filter_map(|(_, addr, token)| Some((addr, token?)))
+            .map(|(addr, token)| self.query(addr, method, Args { token: Some(token), ..args.clone() }))
+            .collect();
+        queries.filter(|result| std::future::ready(result.is_ok())).count().await
+    }
+
+    /// Iteratively query the nodes of a family closest to `target`, with `find_node`, `get_peers`
+    /// or `get` and the given extra arguments
+    async fn lookup(&self, family: Family, target: NodeId, method: &'static str, extra: Args) -> Lookup {
+        let mut candidates: BTreeMap<NodeId, (NodeId, SocketAddr)> = self.state.lock().expect("DHT lock poisoned").tables
+            [family as usize]
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), (node.id, node.addr)))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded: BTreeMap<NodeId, (NodeId, SocketAddr, Option<ByteBuf>)> = BTreeMap::new();
+        let mut peers = HashSet::new();
+        let mut others = HashSet::new();
+        let mut items = Vec::new();
+        let mut filters = BTreeMap::new();
+        let want = (self.families().count() == 2).then(|| Family::ALL.map(|f| ByteBuf::from(f.want())).to_vec());
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            // Query the closest nodes not queried yet, as long as they are closer than the K
+            // closest ones which answered
+            while in_flight.len() < ALPHA {
+                let bound = (responded.len() >= K).then(|| *responded.keys().nth(K - 1).expect("K responses"));
+                let next = candidates
+                    .iter()
+                    .find(|(d, (_, addr))| !queried.contains(addr) && bound.is_none_or(|bound| **d < bound))
+                    .map(|(d, node)| (*d, *node));
+                let Some((d, (id, addr))) = next else {
+                    break;
+                };
+                queried.insert(addr);
+                let mut args = Args { want: want.clone(), ..extra.clone() };
+                if method == "get_peers" {
+                    args.info_hash = Some(ByteBuf::from(target));
+                } else {
+                    args.target = Some(ByteBuf::from(target));
+                }
+                in_flight.push(async move { (d, id, addr, self.query(addr, method, args).await) });
+            }
+            let Some((d, id, addr, result)) = in_flight.next().await else {
+                break;
+            };
+            let Ok(response) = result else {
+                continue;
+            };
+            for value in response.values.iter().flatten() {
+                peers.extend(krpc::decode_peer(value));
+            }
+            for node_family in Family::ALL {
+                let nodes = match node_family {
+                    Family::V4 => &response.nodes,
+                    Family::V6 => &response.nodes6,
+                };
+                for (id, addr) in krpc::decode_nodes(nodes.as_deref().map_or(&[][..], Vec::as_slice), node_family) {
+                    if node_family == family {
+                        candidates.entry(distance(&id, &target)).or_insert((id, addr));
+                    } else {
+                        others.insert(addr);
+                    }
+                }
+            }
+            let token = response.token.clone();
+            let seeds = response.seeds_filter.as_deref().and_then(|bytes| Bloom::from_bytes(bytes));
+            let peers_filter = response.peers_filter.as_deref().and_then(|bytes| Bloom::from_bytes(bytes));
+            if let (Some(seeds), Some(peers)) = (seeds, peers_filter) {
+                filters.insert(d, (seeds, peers));
+            }
+            if response.v.is_some() {
+                items.push(response);
+            }
+            responded.insert(d, (id, addr, token));
+        }
+        Lookup {
+            peers: peers.into_iter().collect(),
+            closest: responded.into_values().take(K).collect(),
+            others,
+            items,
+            filters: filters.into_values().take(K).collect(),
+        }
+    }
+
+    /// Send a query and wait for its response
+    async fn query(&self, addr: SocketAddr, method: &str, mut args: Args) -> anyhow::Result<Response> {
+        let family = Family::of(&addr);
+        let Some(socket) = &self.sockets[family as usize] else {
+            anyhow::bail!("no DHT socket to reach {addr}");
+        };
+        let (transaction, receiver) = {
+            let mut state = self.state.lock().expect("DHT lock poisoned");
+            args.id = ByteBuf::from(*state.tables[family as usize].own());
+            let transaction = state.next_transaction.to_be_bytes();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            let (response, receiver) = oneshot::channel();
+            state.pending.insert(transaction, Pending { addr, response });
+            (transaction, receiver)
+        };
+        let message = serde_bencode::to_bytes(&Krpc::query(&transaction, method, args))?;
+        if let Err(e) = socket.send_to(&message, addr).await {
+            self.state.lock().expect("DHT lock poisoned").pending.remove(&transaction);
+            return Err(e).with_context(|| format!("send {method} to {addr}"));
+        }
+        match tokio::time::timeout(QUERY_TIMEOUT, receiver).await {
+            Ok(response) => response.context("DHT stopped")?,
+            Err(_) => {
+                let mut state = self.state.lock().expect("DHT lock poisoned");
+                state.pending.remove(&transaction);
+                state.tables[family as usize].failed(addr);
+                anyhow::bail!("{method} to {addr} timed out")
+            }
+        }
+    }
+
+    async fn receive(self: Arc<Self>, family: Family) {
+        let socket = self.sockets[family as usize].as_ref().expect("receiving on a bound socket");
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // ICMP errors of previous sends are reported here on some systems
+                    eprintln!("DHT receive: {e}");
+                    continue;
+                }
+            };
+            let Ok(message) = serde_bencode::from_bytes::<Krpc>(&buf[..len]) else {
+                continue; // Not worth answering garbage
+            };
+            if let Some(reply) = self.handle(message, from) {
+                let Ok(reply) = serde_bencode::to_bytes(&reply) else {
+                    continue;
+                };
+                let _ = socket.send_to(&reply, from).await;
+            }
+        }
+    }
+
+    /// Process a message from `from`, returning the reply to send if it is a query
+    fn handle(&self, message: Krpc, from: SocketAddr) -> Option<Krpc> {
+        let mut state = self.state.lock().expect("DHT lock poisoned");
+        match message.kind.as_str() {
+            "q" => {
+                let mut reply = state.answer(&message, from);
+                reply.ip = Some(krpc::encode_peer(from));
+                Some(reply)
+            }
+            "r" | "e" => {
+                let transaction: [u8; 2] = message.transaction.as_slice().try_into().ok()?;
+                if state.pending.get(&transaction)?.addr != from {
+                    return None; // Spoofed or misrouted
+                }
+                let pending = state.pending.remove(&transaction)?;
+                if let Some(external) = message.ip.as_deref().and_then(|ip| krpc::decode_peer(ip)) {
+                    state.vote(external.ip(), from.ip());
+                }
+                let response = match (message.response, message.error) {
+                    (Some(response), _) => match krpc::id(&response.id) {
+                        Some(id) => {
+                            state.seen(id, from);
+                            Ok(response)
+                        }
+                        None => Err(anyhow::anyhow!("response without a valid id")),
+                    },
+                    (None, Some(error)) => Err(anyhow::anyhow!(krpc::describe_error(&error))),
+                    (None, None) => Err(anyhow::anyhow!("empty response")),
+                };
+                let _ = pending.response.send(response);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl State {
+    fn answer(&mut self, message: &Krpc, from: SocketAddr) -> Krpc {
+        let transaction = &message.transaction;
+        let Some((method, args)) = message.method.as_deref().zip(message.args.as_ref()) else {
+            return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "query without method or arguments");
+        };
+        let Some(id) = krpc::id(&args.id) else {
+            return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "invalid node id");
+        };
+        self.seen(id, from);
+        let mut response = Response {
+            id: ByteBuf::from(*self.tables[Family::of(&from) as usize].own()),
+            ..Response::default()
+        };
+        // Nodes of the family of the query unless others are asked for (BEP 32)
+        let wanted = |family: Family| match &args.want {
+            Some(want) => want.iter().any(|want| want.as_slice() == family.want()),
+            None => family == Family::of(&from),
+        };
+        match method {
+            "ping" => {}
+            "find_node" => {
+                let Some(target) = args.target.as_ref().and_then(krpc::id) else {
+                    return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "invalid target");
+                };
+                self.add_closest(&mut response, &target, wanted);
+            }
+            "get_peers" => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(krpc::id) else {
+                    return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "invalid info hash");
+                };
+                response.token = Some(ByteBuf::from(self.token(from, 0)));
+                let peers = self.peers(&info_hash);
+                if args.scrape == Some(1) {
+                    let (mut seeds, mut downloaders) = (Bloom::default(), Bloom::default());
+                    for (peer, announced) in &peers {
+                        let filter = if announced.seed { &mut seeds } else { &mut downloaders };
+                        filter.insert(peer.ip());
+                    }
+                    response.seeds_filter = Some(ByteBuf::from(seeds.as_bytes()));
+                    response.peers_filter = Some(ByteBuf::from(downloaders.as_bytes()));
+                }
+                let values: Vec<ByteBuf> = peers
+                    .into_iter()
+                    .filter(|(peer, announced)| Family::of(peer) == Family::of(&from) && !(announced.seed && args.noseed == Some(1)))
+                    .take(MAX_VALUES)
+                    .map(|(peer, _)| krpc::encode_peer(peer))
+                    .collect();
+                if values.is_empty() {
+                    self.add_closest(&mut response, &info_hash, wanted);
+                } else {
+                    response.values = Some(values);
+                }
+            }
+            "announce_peer" => {
+                let Some(info_hash) = args.info_hash.as_ref().and_then(krpc::id) else {
+                    return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "invalid info hash");
+                };
+                if !self.valid_token(args.token.as_ref(), from) {
+                    return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "bad token");
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "missing port"),
+                };
+                self.announced(info_hash, SocketAddr::new(from.ip(), port), args.seed == Some(1));
+            }
+            "sample_infohashes" => {
+                let Some(target) = args.target.as_ref().and_then(krpc::id) else {
+                    return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "invalid target");
+                };
+                let stored = self.info_hashes();
+                if self.sampled.elapsed() >= SAMPLE_INTERVAL || self.samples.len() < SAMPLES.min(stored.len()) {
+                    self.samples = stored.choose_multiple(&mut rand::thread_rng(), SAMPLES).copied().collect();
+                    self.sampled = Instant::now();
+                }
+                response.interval = Some(SAMPLE_INTERVAL.saturating_sub(self.sampled.elapsed()).as_secs() as i64);
+                response.num = Some(stored.len() as i64);
+                response.samples = Some(ByteBuf::from(self.samples.concat()));
+                self.add_closest(&mut response, &target, wanted);
+            }
+            "get" => {
+                let Some(target) = args.target.as_ref().and_then(krpc::id) else {
+                    return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "invalid target");
+                };
+                response.token = Some(ByteBuf::from(self.token(from, 0)));
+                let item = self.items.get(&target).filter(|(_, stored)| stored.elapsed() < ITEM_TTL);
+                if let Some((item, _)) = item {
+                    match &item.signed {
+                        Some(signed) => {
+                            response.seq = Some(signed.seq);
+                            // Versions the querying node already has are not sent again
+                            if args.seq.is_none_or(|seq| seq < signed.seq) {
+                                response.v = Some(item.value.clone());
+                                response.k = Some(ByteBuf::from(signed.key));
+                                response.sig = Some(ByteBuf::from(signed.signature));
+                            }
+                        }
+                        None => response.v = Some(item.value.clone()),
+                    }
+                }
+                self.add_closest(&mut response, &target, wanted);
+            }
+            "put" => {
+                if !self.valid_token(args.token.as_ref(), from) {
+                    return Krpc::error(transaction, krpc::PROTOCOL_ERROR, "bad token");
+                }
+                if let Err((code, message)) = self.put(args) {
+                    return Krpc::error(transaction, code, message);
+                }
+            }
+            _ => return Krpc::error(transaction, krpc::METHOD_UNKNOWN, "method unknown"),
+        }
+        Krpc::response(transaction, response)
+    }
+
+    /// Store an item put by a node, unless it is invalid or older than the one we have
+    fn put(&mut self, args: &Args) -> Result<(), (i64, &'static str)> {
+        let value = args.v.clone().ok_or((krpc::PROTOCOL_ERROR, "missing value"))?;
+        let signed = match (&args.k, &args.sig, args.seq) {
+            (None, None, None) => None,
+            (Some(key), Some(signature), Some(seq)) => Some(Signed {
+                key: key.as_slice().try_into().map_err(|_| (krpc::PROTOCOL_ERROR, "invalid key"))?,
+                signature: signature.as_slice().try_into().map_err(|_| (krpc::INVALID_SIGNATURE, "invalid signature"))?,
+                seq,
+                salt: args.salt.clone().map(ByteBuf::into_vec).unwrap_or_default(),
+            }),
+            _ => return Err((krpc::PROTOCOL_ERROR, "incomplete mutable item")),
+        };
+        let item = Item { value, signed };
+        item.validate()?;
+        let target = item.target().map_err(|_| (krpc::PROTOCOL_ERROR, "invalid value"))?;
+
+        self.items.retain(|_, (_, stored)| stored.elapsed() < ITEM_TTL);
+        if let (Some(signed), Some((current, _))) = (&item.signed, self.items.get(&target)) {
+            let current_seq = seq_of(current);
+            if args.cas.is_some_and(|cas| cas != current_seq) {
+                return Err((krpc::CAS_MISMATCH, "CAS mismatched, re-read value and try again"));
+            }
+            if signed.seq < current_seq || (signed.seq == current_seq && item.value != current.value) {
+                return Err((krpc::SEQ_TOO_LOW, "sequence number less than current"));
+            }
+        }
+        if self.items.len() >= MAX_ITEMS && !self.items.contains_key(&target) {
+            let oldest = self.items.iter().min_by_key(|(_, (_, stored))| *stored).map(|(target, _)| *target);
+            self.items.remove(&oldest.expect("items stored"));
+        }
+        self.items.insert(target, (item, Instant::now()));
+        Ok(())
+    }
+
+    /// Give the nodes closest to `target` of the wanted families
+    fn add_closest(&self, response: &mut Response, target: &NodeId, wanted: impl Fn(Family) -> bool) {
+        let closest = |family: Family| {
+            let nodes = self.tables[family as usize].closest(target, K).into_iter();
+            krpc::encode_nodes(nodes.map(|node| (node.id, node.addr)))
+        };
+        response.nodes = wanted(Family::V4).then(|| closest(Family::V4));
+        response.nodes6 = wanted(Family::V6).then(|| closest(Family::V6));
+    }
+
+    /// Record that a node was heard from, unless its id does not match its address and such
+    /// nodes are refused
+    fn seen(&mut self, id: NodeId, addr: SocketAddr) {
+        if self.enforce_node_ids && !security::is_compliant(&id, addr.ip()) {
+            return;
+        }
+        self.tables[Family::of(&addr) as usize].seen(id, addr);
+    }
+
+    /// Count a report of our external address by `voter`. Once enough nodes agree, our id for
+    /// that family is changed to one matching it, unless it already does.
+    fn vote(&mut self, external: IpAddr, voter: IpAddr) {
+        if external.is_ipv4() != voter.is_ipv4() {
+            return;
+        }
+        if self.votes.len() > 16 * EXTERNAL_VOTES {
+            self.votes.clear(); // Nodes lying about our address
+        }
+        let voters = self.votes.entry(external).or_default();
+        voters.insert(voter);
+        if voters.len() < EXTERNAL_VOTES {
+            return;
+        }
+        self.votes.retain(|ip, _| ip.is_ipv4() != external.is_ipv4());
+        let family = if external.is_ipv4() { Family::V4 } else { Family::V6 };
+        let table = &mut self.tables[family as usize];
+        if !security::is_compliant(table.own(), external) {
+            eprintln!("DHT node id changed to match our external address {external}");
+            table.set_own(security::node_id(external));
+        }
+    }
+
+    /// Store a peer announced for a torrent, forgetting expired peers and, beyond the limits, the
+    /// least recently announced ones
+    fn announced(&mut self, info_hash: [u8; 20], peer: SocketAddr, seed: bool) {
+        if self.peers.len() >= MAX_TORRENTS && !self.peers.contains_key(&info_hash) {
+            self.expire();
+            if self.peers.len() >= MAX_TORRENTS {
+                let oldest = self
+                    .peers
+                    .iter()
+                    .min_by_key(|(_, peers)| peers.values().map(|announced| announced.at).max())
+                    .map(|(info_hash, _)| *info_hash);
+                self.peers.remove(&oldest.expect("torrents stored"));
+            }
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        peers.retain(|_, announced| announced.at.elapsed() < PEER_TTL);
+        if peers.len() >= MAX_TORRENT_PEERS && !peers.contains_key(&peer) {
+            let oldest = peers.iter().min_by_key(|(_, announced)| announced.at).map(|(peer, _)| *peer);
+            peers.remove(&oldest.expect("peers stored"));
+        }
+        peers.insert(peer, Announced { at: Instant::now(), seed });
+    }
+
+    /// Live peers announced for a torrent
+    fn peers(&mut self, info_hash: &[u8; 20]) -> Vec<(SocketAddr, Announced)> {
+        let Some(peers) = self.peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|_, announced| announced.at.elapsed() < PEER_TTL);
+        peers.iter().map(|(peer, announced)| (*peer, *announced)).collect()
+    }
+
+    /// Info hashes of the torrents with live peers
+    fn info_hashes(&mut self) -> Vec<[u8; 20]> {
+        self.expire();
+        self.peers.keys().copied().collect()
+    }
+
+    /// Forget the expired peers, and the torrents left without any
+    fn expire(&mut self) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|_, announced| announced.at.elapsed() < PEER_TTL);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+
+    fn valid_token(&mut self, token: Option<&ByteBuf>, from: SocketAddr) -> bool {
+        token.is_some_and(|token| (0..2).any(|i| token.as_slice() == self.token(from, i)))
+    }
+
+    /// Token proving a node queried `get_peers` from `addr`, derived from the current secret or
+    /// the `previous` one, which are rotated periodically
+    fn token(&mut self, addr: SocketAddr, previous: usize) -> [u8; 20] {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.secrets = [rand::random(), self.secrets[0]];
+            self.rotated = Instant::now();
+        }
+        let mut hasher = Sha1::new();
+        hasher.update(self.secrets[previous]);
+        match addr.ip() {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// Version of a mutable item
+fn seq_of(item: &Item) -> i64 {
+    item.signed.as_ref().map_or(i64::MIN, |signed| signed.seq)
+}
+
+/// A UDP socket, IPv6 ones not accepting IPv4 so that both families can share a port
+fn bind_socket(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn node() -> (Arc<Dht>, SocketAddr) {
+        let dht = Dht::bind(&["127.0.0.1:0".parse().unwrap()], None, false).await.unwrap();
+        let addr = dht.sockets[Family::V4 as usize].as_ref().unwrap().local_addr().unwrap();
+        (dht, addr)
+    }
+
+    /// Nodes on localhost, having all joined through the first one
+    async fn swarm(size: usize) -> Vec<Arc<Dht>> {
+        let mut nodes = Vec::new();
+        let (first, entry) = node().await;
+        nodes.push(first);
+        for _ in 1..size {
+            nodes.push(node().await.0);
+        }
+        for node in &nodes[1..] {
+            node.bootstrap(&[entry]).await;
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn nodes_find_each_other() {
+        let nodes = swarm(12).await;
+        for node in &nodes {
+            assert!(node.nodes() >= K, "a node only knows of {} others", node.nodes());
+        }
+        let id = *nodes[5].state.lock().unwrap().tables[Family::V4 as usize].own();
+        let lookup = nodes[9].lookup(Family::V4, id, "find_node", Args::default()).await;
+        let addr = nodes[5].sockets[Family::V4 as usize].as_ref().unwrap().local_addr().unwrap();
+        assert!(lookup.closest.iter().any(|(found, node, _)| *found == id && *node == addr));
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_found() {
+        let nodes = swarm(12).await;
+        let info_hash = [0x42; 20];
+        assert!(nodes[3].announce(info_hash, 6881, false).await.is_empty());
+        let peers = nodes[7].announce(info_hash, 6882, true).await;
+        assert_eq!(peers, ["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+        let mut peers = nodes[11].announce(info_hash, 6883, false).await;
+        peers.sort();
+        assert_eq!(peers, ["127.0.0.1:6881".parse::<SocketAddr>().unwrap(), "127.0.0.1:6882".parse().unwrap()]);
+
+        // Scrape filters hold IP addresses, all the same on localhost
+        let scrape = nodes[0].scrape(info_hash).await;
+        assert!(scrape.seeds.round() == 1.0 && scrape.peers.round() == 1.0, "{scrape:?}");
+        assert!(nodes[0].crawl(10).await.contains(&info_hash));
+    }
+
+    #[tokio::test]
+    async fn saved_state_restores_ids_and_nodes() {
+        let (dht, _) = node().await;
+        let mut nodes: Vec<SocketAddr> = vec!["10.0.0.1:6881".parse().unwrap(), "[2001:db8::1]:6881".parse().unwrap()];
+        let ids = {
+            let mut state = dht.state.lock().unwrap();
+            for &addr in &nodes {
+                state.tables[Family::of(&addr) as usize].seen(rand::random(), addr);
+            }
+            state.tables.clone().map(|table| *table.own())
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dht").join("state");
+        dht.save(&path).await.unwrap();
+
+        let saved = Saved::load(&path).await.unwrap().unwrap();
+        let mut saved_nodes = saved.nodes();
+        saved_nodes.sort();
+        nodes.sort();
+        assert_eq!(saved_nodes, nodes);
+        let restored = Dht::bind(&["127.0.0.1:0".parse().unwrap()], Some(&saved), false).await.unwrap();
+        assert_eq!(restored.state.lock().unwrap().tables.clone().map(|table| *table.own()), ids);
+        assert!(Saved::load(&dir.path().join("missing")).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn answers_give_nodes_of_the_wanted_families() {
+        let (dht, _) = node().await;
+        let mut state = dht.state.lock().unwrap();
+        let (node4, node6) = (([4; 20], "10.0.0.1:6881".parse().unwrap()), ([6; 20], "[2001:db8::1]:6881".parse().unwrap()));
+        for (id, addr) in [node4, node6] {
+            state.seen(id, addr);
+        }
+        let querier: SocketAddr = "[2001:db8::2]:6881".parse().unwrap();
+        let mut find_node = |want: Option<&[&[u8]]>| {
+            let args = Args {
+                id: ByteBuf::from([7; 20]),
+                target: Some(ByteBuf::from([0; 20])),
+                want: want.map(|want| want.iter().map(|&family| ByteBuf::from(family)).collect()),
+                ..Args::default()
+            };
+            state.answer(&Krpc::query(b"aa", "find_node", args), querier).response.unwrap()
+        };
+
+        // Nodes of the family of the query by default, which it is answered from
+        let response = find_node(None);
+        assert_eq!(response.nodes, None);
+        let nodes6 = krpc::decode_nodes(response.nodes6.as_ref().unwrap(), Family::V6);
+        assert!(nodes6.contains(&node6) && nodes6.contains(&([7; 20], querier)));
+        let response = find_node(Some(&[b"n4"]));
+        assert_eq!(krpc::decode_nodes(response.nodes.as_ref().unwrap(), Family::V4), [node4]);
+        assert_eq!(response.nodes6, None);
+        let response = find_node(Some(&[b"n4", b"n6"]));
+        assert!(response.nodes.is_some() && response.nodes6.is_some());
+        assert_eq!(response.id.as_slice(), state.tables[Family::V6 as usize].own());
+        assert_eq!(state.tables[Family::V4 as usize].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn nodes_with_ids_not_matching_their_address_are_refused() {
+        let (dht, _) = node().await;
+        let mut state = dht.state.lock().unwrap();
+        state.enforce_node_ids = true;
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        state.seen([0; 20], SocketAddr::new(ip, 6881));
+        state.seen(security::node_id(ip), SocketAddr::new(ip, 6882));
+        // Local nodes cannot be checked
+        state.seen([1; 20], "192.168.1.2:6881".parse().unwrap());
+        let mut nodes: Vec<SocketAddr> = state.tables[Family::V4 as usize].nodes().map(|node| node.addr).collect();
+        nodes.sort();
+        assert_eq!(nodes, [SocketAddr::new(ip, 6882), "192.168.1.2:6881".parse().unwrap()]);
+    }
+
+    /// Arguments of a `put` of `item`
+    fn put_args(item: &Item, cas: Option<i64>) -> Args {
+        let signed = item.signed.as_ref();
+        Args {
+            v: Some(item.value.clone()),
+            k: signed.map(|signed| ByteBuf::from(signed.key)),
+            sig: signed.map(|signed| ByteBuf::from(signed.signature)),
+            seq: signed.map(|signed| signed.seq),
+            salt: signed.map(|signed| ByteBuf::from(signed.salt.clone())),
+            cas,
+            ..Args::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn mutable_items_are_replaced_by_newer_versions_only() {
+        let (dht, _) = node().await;
+        let mut state = dht.state.lock().unwrap();
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let version = |seq, value: &str| Item::signed(&key, b"salt", seq, Value::Bytes(value.into())).unwrap();
+        let stored = |state: &State| state.items[&version(1, "").target().unwrap()].0.clone();
+
+        assert_eq!(state.put(&put_args(&version(2, "two"), None)), Ok(()));
+        assert_eq!(state.put(&put_args(&version(1, "one"), None)).unwrap_err().0, krpc::SEQ_TOO_LOW);
+        assert_eq!(state.put(&put_args(&version(2, "other"), None)).unwrap_err().0, krpc::SEQ_TOO_LOW);
+        assert_eq!(state.put(&put_args(&version(2, "two"), None)), Ok(()));
+        // Compare and swap, against the sequence number of the version stored
+        assert_eq!(state.put(&put_args(&version(3, "three"), Some(1))).unwrap_err().0, krpc::CAS_MISMATCH);
+        assert_eq!(stored(&state), version(2, "two"));
+        assert_eq!(state.put(&put_args(&version(3, "three"), Some(2))), Ok(()));
+        assert_eq!(stored(&state), version(3, "three"));
+
+        let mut forged = put_args(&version(4, "four"), None);
+        forged.v = Some(Value::Bytes(b"forged".to_vec()));
+        assert_eq!(state.put(&forged).unwrap_err().0, krpc::INVALID_SIGNATURE);
+        let mut incomplete = put_args(&version(4, "four"), None);
+        incomplete.sig = None;
+        assert_eq!(state.put(&incomplete).unwrap_err().0, krpc::PROTOCOL_ERROR);
+        assert_eq!(stored(&state), version(3, "three"));
+    }
+
+    #[tokio::test]
+    async fn items_put_are_found() {
+        let nodes = swarm(12).await;
+        let value = Value::Bytes(b"Hello World!".to_vec());
+        let immutable = Item { value: value.clone(), signed: None };
+        assert!(nodes[2].put(&immutable).await.unwrap() > 0);
+        assert_eq!(nodes[9].get(immutable.target().unwrap()).await, Some(value));
+        assert_eq!(nodes[9].get([0x42; 20]).await, None);
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let public = key.verifying_key().to_bytes();
+        for seq in 1..=2 {
+            let item = Item::signed(&key, b"salt", seq, Value::Int(seq)).unwrap();
+            assert!(nodes[4].put(&item).await.unwrap() > 0);
+        }
+        let latest = nodes[11].get_mutable(&public, b"salt").await.unwrap();
+        assert_eq!((latest.value, latest.signed.unwrap().seq), (Value::Int(2), 2));
+        assert!(nodes[11].get_mutable(&public, b"other salt").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_capped() {
+        let (dht, _) = node().await;
+        let mut state = dht.state.lock().unwrap();
+        for port in 0..MAX_TORRENT_PEERS as u16 + 10 {
+            state.announced([0; 20], SocketAddr::from(([10, 0, 0, 1], port)), false);
+        }
+        let peers = state.peers(&[0; 20]);
+        assert_eq!(peers.len(), MAX_TORRENT_PEERS);
+        // The least recently announced went first
+        assert!(!peers.iter().any(|(peer, _)| peer.port() < 10));
+
+        for i in 0..MAX_TORRENTS as u32 + 10 {
+            let mut info_hash = [0; 20];
+            info_hash[..4].copy_from_slice(&(i + 1).to_be_bytes());
+            state.announced(info_hash, SocketAddr::from(([10, 0, 0, 1], 1)), false);
+        }
+        assert_eq!(state.peers.len(), MAX_TORRENTS);
+        assert!(!state.peers.contains_key(&[0; 20]));
+
+        // Expired peers make room on the next announce
+        for peers in state.peers.values_mut() {
+            for announced in peers.values_mut() {
+                announced.at -= PEER_TTL;
+            }
+        }
+        state.announced([0xff; 20], SocketAddr::from(([10, 0, 0, 1], 1)), false);
+        assert_eq!(state.peers.len(), 1);
+    }
+}
@@ -0,0 +1,55 @@
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+
+/// Number of bits of a filter
+const BITS: usize = 2048;
+
+/// Bloom filter of the addresses of the peers of a torrent, as returned by `get_peers` scrapes
+/// (BEP 33). Filters of several nodes are merged into one, from which the number of distinct
+/// peers is estimated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bloom([u8; BITS / 8]);
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Self([0; BITS / 8])
+    }
+}
+
+impl Bloom {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Set the two bits given by the SHA-1 of the address
+    pub fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+            IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+        };
+        for index in [usize::from(hash[0]) | usize::from(hash[1]) << 8, usize::from(hash[2]) | usize::from(hash[3]) << 8] {
+            let index = index % BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn union(&mut self, other: &Bloom) {
+        for (byte, other) in self.0.iter_mut().zip(other.0) {
+            *byte |= other;
+        }
+    }
+
+    /// Number of addresses inserted, estimated from the proportion of bits left unset
+    pub fn estimate(&self) -> f64 {
+        let unset = self.0.iter().map(|byte| byte.count_zeros() as usize).sum::<usize>();
+        if unset == BITS {
+            return 0.0;
+        }
+        let m = BITS as f64;
+        (unset.max(1) as f64 / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+    }
+}
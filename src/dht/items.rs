@@ -0,0 +1,184 @@
+use super::routing::NodeId;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+
+/// Largest bencoded value an item may hold
+pub const MAX_VALUE: usize = 1000;
+/// Largest salt of a mutable item
+pub const MAX_SALT: usize = 64;
+
+/// A value stored in the DHT (BEP 44), under the hash of the value itself if it is immutable or of
+/// the public key of its owner, who can update it, if it is mutable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub value: Value,
+    pub signed: Option<Signed>,
+}
+
+/// What makes an item mutable: every version is signed with the key of its owner, and a greater
+/// sequence number replaces the previous ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signed {
+    pub key: [u8; 32],
+    pub signature: [u8; 64],
+    pub seq: i64,
+    /// Lets one key own several items
+    pub salt: Vec<u8>,
+}
+
+impl Item {
+    /// A version of a mutable item, signed with `key`
+    pub fn signed(key: &SigningKey, salt: &[u8], seq: i64, value: Value) -> anyhow::Result<Self> {
+        let encoded = serde_bencode::to_bytes(&value)?;
+        let signature = key.sign(&signable(salt, seq, &encoded));
+        Ok(Self {
+            value,
+            signed: Some(Signed {
+                key: key.verifying_key().to_bytes(),
+                signature: signature.to_bytes(),
+                seq,
+                salt: salt.to_vec(),
+            }),
+        })
+    }
+
+    /// Key the item is stored under
+    pub fn target(&self) -> anyhow::Result<NodeId> {
+        Ok(match &self.signed {
+            Some(signed) => mutable_target(&signed.key, &signed.salt),
+            None => sha1(&serde_bencode::to_bytes(&self.value)?),
+        })
+    }
+
+    /// Check the limits of the specification and, for a mutable item, its signature. Errors are
+    /// KRPC error codes and messages.
+    pub fn validate(&self) -> Result<(), (i64, &'static str)> {
+        let encoded = serde_bencode::to_bytes(&self.value).map_err(|_| (super::krpc::PROTOCOL_ERROR, "invalid value"))?;
+        if encoded.len() > MAX_VALUE {
+            return Err((super::krpc::MESSAGE_TOO_BIG, "message (v field) too big"));
+        }
+        let Some(signed) = &self.signed else {
+            return Ok(());
+        };
+        if signed.salt.len() > MAX_SALT {
+            return Err((super::krpc::SALT_TOO_BIG, "salt (salt field) too big"));
+        }
+        let valid = VerifyingKey::from_bytes(&signed.key).is_ok_and(|key| {
+            let signature = Signature::from_bytes(&signed.signature);
+            key.verify_strict(&signable(&signed.salt, signed.seq, &encoded), &signature).is_ok()
+        });
+        if !valid {
+            return Err((super::krpc::INVALID_SIGNATURE, "invalid signature"));
+        }
+        Ok(())
+    }
+}
+
+/// Key a mutable item is stored under
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+fn sha1(bytes: &[u8]) -> NodeId {
+    Sha1::digest(bytes).into()
+}
+
+/// What the owner of a mutable item signs: its salt, sequence number and value, bencoded as
+/// in a dictionary without the enclosing `d` and `e`
+fn signable(salt: &[u8], seq: i64, encoded: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if !salt.is_empty() {
+        bytes.extend(format!("4:salt{}:", salt.len()).as_bytes());
+        bytes.extend(salt);
+    }
+    bytes.extend(format!("3:seqi{seq}e1:v").as_bytes());
+    bytes.extend(encoded);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::krpc;
+
+    /// Public key of the test vectors of BEP 44, which all hold this value
+    const KEY: &str = "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548";
+
+    fn hello() -> Value {
+        Value::Bytes(b"Hello World!".to_vec())
+    }
+
+    fn vector(salt: &[u8], signature: &str) -> Item {
+        let signed = Signed {
+            key: hex::decode(KEY).unwrap().try_into().unwrap(),
+            signature: hex::decode(signature).unwrap().try_into().unwrap(),
+            seq: 1,
+            salt: salt.to_vec(),
+        };
+        Item { value: hello(), signed: Some(signed) }
+    }
+
+    #[test]
+    fn immutable_items_are_stored_under_the_hash_of_their_value() {
+        let item = Item { value: hello(), signed: None };
+        assert_eq!(hex::encode(item.target().unwrap()), "e5f96f6f38320f0f33959cb4d3d656452117aadb");
+        assert_eq!(item.validate(), Ok(()));
+    }
+
+    #[test]
+    fn mutable_items_match_the_test_vectors() {
+        let vectors = [
+            (
+                &b""[..],
+                "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01",
+                "4a533d47ec9c7d95b1ad75f576cffc641853b750",
+                &b"3:seqi1e1:v12:Hello World!"[..],
+            ),
+            (
+                &b"foobar"[..],
+                "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+                "411eba73b6f087ca51a3795d9c8c938d365e32c1",
+                &b"4:salt6:foobar3:seqi1e1:v12:Hello World!"[..],
+            ),
+        ];
+        for (salt, signature, target, signable_bytes) in vectors {
+            let item = vector(salt, signature);
+            assert_eq!(signable(salt, 1, b"12:Hello World!"), signable_bytes);
+            assert_eq!(hex::encode(item.target().unwrap()), target);
+            assert_eq!(item.validate(), Ok(()));
+
+            // The signature covers the sequence number, the salt and the value
+            let mut tampered = item.clone();
+            tampered.signed.as_mut().unwrap().seq = 2;
+            assert_eq!(tampered.validate().unwrap_err().0, krpc::INVALID_SIGNATURE);
+            let mut tampered = item.clone();
+            tampered.signed.as_mut().unwrap().salt = b"other".to_vec();
+            assert_eq!(tampered.validate().unwrap_err().0, krpc::INVALID_SIGNATURE);
+            let tampered = Item { value: Value::Bytes(b"Hello World?".to_vec()), ..item };
+            assert_eq!(tampered.validate().unwrap_err().0, krpc::INVALID_SIGNATURE);
+        }
+    }
+
+    #[test]
+    fn signed_items_are_valid() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let item = Item::signed(&key, b"salt", 5, hello()).unwrap();
+        assert_eq!(item.validate(), Ok(()));
+        assert_eq!(item.target().unwrap(), mutable_target(&key.verifying_key().to_bytes(), b"salt"));
+        assert_ne!(item.target().unwrap(), mutable_target(&key.verifying_key().to_bytes(), b""));
+    }
+
+    #[test]
+    fn items_over_the_limits_are_refused() {
+        let big = Item { value: Value::Bytes(vec![0; MAX_VALUE]), signed: None };
+        assert_eq!(big.validate().unwrap_err().0, krpc::MESSAGE_TOO_BIG);
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let salted = Item::signed(&key, &[0; MAX_SALT + 1], 1, hello()).unwrap();
+        assert_eq!(salted.validate().unwrap_err().0, krpc::SALT_TOO_BIG);
+        assert_eq!(Item::signed(&key, &[0; MAX_SALT], 1, hello()).unwrap().validate(), Ok(()));
+    }
+}
@@ -0,0 +1,187 @@
+use super::routing::{Family, NodeId};
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, SocketAddr};
+
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+pub const MESSAGE_TOO_BIG: i64 = 205;
+pub const INVALID_SIGNATURE: i64 = 206;
+pub const SALT_TOO_BIG: i64 = 207;
+pub const CAS_MISMATCH: i64 = 301;
+pub const SEQ_TOO_LOW: i64 = 302;
+
+/// A KRPC message: a query, a response or an error, bencoded in a single UDP datagram
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Krpc {
+    #[serde(rename = "t")]
+    pub transaction: ByteBuf,
+    #[serde(rename = "y")]
+    pub kind: String, // "q", "r" or "e"
+    #[serde(rename = "q", default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<Args>,
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<Response>,
+    /// Error code and message, as a list
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<Vec<Value>>,
+    /// Compact address the query came from, in responses, for nodes to learn their external
+    /// address (BEP 42)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<ByteBuf>,
+}
+
+/// Arguments of every query, each method using a subset of them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Args {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>, // find_node, get, sample_infohashes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>, // get_peers, announce_peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>, // announce_peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>, // announce_peer: use the source port of the query instead of `port`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u8>, // announce_peer: the peer has the whole torrent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scrape: Option<u8>, // get_peers: ask for bloom filters of the seeds and peers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noseed: Option<u8>, // get_peers: leave seeds out of the values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>, // announce_peer, put
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<Value>, // put: the value of the item
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<ByteBuf>, // put: public key of a mutable item
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig: Option<ByteBuf>, // put: signature of a mutable item
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>, // get: version already known, put: version of a mutable item
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<ByteBuf>, // put
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cas: Option<i64>, // put: only replace this version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub want: Option<Vec<ByteBuf>>, // find_node, get_peers: families of the nodes wanted, "n4" and "n6"
+}
+
+/// Values of every response, each method using a subset of them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Response {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>, // Compact node infos
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes6: Option<ByteBuf>, // Compact node infos of IPv6 nodes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>, // Compact peer infos
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<Value>, // get: the item, and for a mutable one:
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(rename = "BFsd", default, skip_serializing_if = "Option::is_none")]
+    pub seeds_filter: Option<ByteBuf>, // get_peers scrape
+    #[serde(rename = "BFpe", default, skip_serializing_if = "Option::is_none")]
+    pub peers_filter: Option<ByteBuf>, // get_peers scrape
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<i64>, // sample_infohashes: seconds before the samples change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num: Option<i64>, // sample_infohashes: number of info hashes stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<ByteBuf>, // sample_infohashes: some of the info hashes stored, concatenated
+}
+
+impl Krpc {
+    pub fn query(transaction: &[u8], method: &str, args: Args) -> Self {
+        Self {
+            transaction: ByteBuf::from(transaction),
+            kind: "q".into(),
+            method: Some(method.into()),
+            args: Some(args),
+            ..Self::default()
+        }
+    }
+
+    pub fn response(transaction: &[u8], response: Response) -> Self {
+        Self {
+            transaction: ByteBuf::from(transaction),
+            kind: "r".into(),
+            response: Some(response),
+            ..Self::default()
+        }
+    }
+
+    pub fn error(transaction: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            transaction: ByteBuf::from(transaction),
+            kind: "e".into(),
+            error: Some(vec![Value::Int(code), Value::Bytes(message.into())]),
+            ..Self::default()
+        }
+    }
+}
+
+/// Code and message of an error
+pub fn describe_error(error: &[Value]) -> String {
+    match error {
+        [Value::Int(code), Value::Bytes(message)] => format!("error {code}: {}", String::from_utf8_lossy(message)),
+        _ => "malformed error".into(),
+    }
+}
+
+/// A 20 byte string out of a message
+pub fn id(bytes: &ByteBuf) -> Option<NodeId> {
+    bytes.as_slice().try_into().ok()
+}
+
+/// Compact peer info: the address followed by the port, in 6 bytes for IPv4 or 18 for IPv6
+pub fn encode_peer(addr: SocketAddr) -> ByteBuf {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend(addr.port().to_be_bytes());
+    ByteBuf::from(bytes)
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = bytes.split_at(bytes.len().checked_sub(2)?);
+    let ip = match ip.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+/// Compact node infos: the 20 byte id of each node followed by its compact address
+pub fn encode_nodes(nodes: impl IntoIterator<Item = (NodeId, SocketAddr)>) -> ByteBuf {
+    let mut bytes = Vec::new();
+    for (id, addr) in nodes {
+        bytes.extend(id);
+        bytes.extend(encode_peer(addr).into_vec());
+    }
+    ByteBuf::from(bytes)
+}
+
+/// Decode compact node infos of the given family, ignoring a truncated trailing entry
+pub fn decode_nodes(bytes: &[u8], family: Family) -> Vec<(NodeId, SocketAddr)> {
+    bytes
+        .chunks_exact(20 + family.compact_len())
+        .filter_map(|chunk| {
+            let (id, addr) = chunk.split_at(20);
+            Some((id.try_into().ok()?, decode_peer(addr)?))
+        })
+        .collect()
+}
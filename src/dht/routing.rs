@@ -0,0 +1,167 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub type NodeId = [u8; 20];
+
+/// Address family, the IPv4 and IPv6 DHTs being separate networks with their own routing tables
+/// (BEP 32)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub const ALL: [Family; 2] = [Family::V4, Family::V6];
+
+    pub fn of(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(_) => Family::V4,
+            SocketAddr::V6(_) => Family::V6,
+        }
+    }
+
+    /// Length of a compact address
+    pub fn compact_len(self) -> usize {
+        match self {
+            Family::V4 => 6,
+            Family::V6 => 18,
+        }
+    }
+
+    /// Name of the family in the `want` argument of queries
+    pub fn want(self) -> &'static [u8] {
+        match self {
+            Family::V4 => b"n4",
+            Family::V6 => b"n6",
+        }
+    }
+}
+
+/// Number of nodes per bucket, and of nodes returned by lookups
+pub const K: usize = 8;
+/// Nodes not heard from for longer are questionable, and replaced first when a bucket is full
+const GOOD_FOR: Duration = Duration::from_secs(15 * 60);
+/// Nodes failing to answer this many queries in a row are bad
+const MAX_FAILURES: u32 = 2;
+
+/// XOR metric between two ids
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    failures: u32,
+}
+
+impl Node {
+    pub fn is_good(&self) -> bool {
+        self.failures == 0 && self.last_seen.elapsed() < GOOD_FOR
+    }
+
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/// Kademlia routing table: nodes are kept in one bucket per length of the prefix their id shares
+/// with ours, so that we know many nodes close to us and a few far away.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Node>>, // Least recently seen first
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn own(&self) -> &NodeId {
+        &self.own
+    }
+
+    /// Change our id, keeping the nodes which still fit in their new buckets
+    pub fn set_own(&mut self, own: NodeId) {
+        let nodes: Vec<Node> = std::mem::replace(self, Self::new(own)).buckets.into_iter().flatten().collect();
+        for node in nodes {
+            if let Some(bucket) = self.bucket(&node.id) {
+                if self.buckets[bucket].len() < K {
+                    self.buckets[bucket].push(node);
+                }
+            }
+        }
+    }
+
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own, id);
+        let zeros = distance
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    /// Record that a node was heard from. A full bucket makes room by evicting a bad node, or
+    /// else a questionable one; good nodes are never evicted for new ones.
+    pub fn seen(&mut self, id: NodeId, addr: SocketAddr) {
+        let Some(bucket) = self.bucket(&id) else {
+            return; // Ourselves
+        };
+        let bucket = &mut self.buckets[bucket];
+        if let Some(pos) = bucket.iter().position(|n| n.id == id) {
+            let mut node = bucket.remove(pos);
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            bucket.push(node);
+            return;
+        }
+        if bucket.len() >= K {
+            let evicted = bucket
+                .iter()
+                .position(Node::is_bad)
+                .or_else(|| bucket.iter().position(|n| !n.is_good()));
+            let Some(evicted) = evicted else {
+                return;
+            };
+            bucket.remove(evicted);
+        }
+        bucket.push(Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        });
+    }
+
+    /// Record that a node did not answer a query
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for node in self.buckets.iter_mut().flatten().filter(|n| n.addr == addr) {
+            node.failures += 1;
+        }
+    }
+
+    /// The `n` known nodes closest to `target`, bad ones excepted
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.nodes().filter(|n| !n.is_bad()).cloned().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
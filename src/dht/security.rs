@@ -0,0 +1,106 @@
+use super::routing::NodeId;
+use std::net::IpAddr;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// A node id bound to the external address `ip` (BEP 42): its first 21 bits are derived from the
+/// address and from 3 random bits kept in its last byte, so that a node cannot pick its position
+/// in the DHT freely.
+pub fn node_id(ip: IpAddr) -> NodeId {
+    let mut id: NodeId = rand::random();
+    let crc = crc(ip, id[19]);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id
+}
+
+/// Whether `id` may be used by a node at `ip`. Nodes on local networks are exempt, their
+/// external address being unknown to us.
+pub fn is_compliant(id: &NodeId, ip: IpAddr) -> bool {
+    if is_local(ip) {
+        return true;
+    }
+    let crc = crc(ip, id[19]);
+    id[0] == (crc >> 24) as u8 && id[1] == (crc >> 16) as u8 && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+/// CRC32-C of the masked leading bytes of the address, with the 3 low bits of `rand` on top
+fn crc(ip: IpAddr, rand: u8) -> u32 {
+    let mut bytes = match ip {
+        IpAddr::V4(ip) => std::array::from_fn::<u8, 4, _>(|i| ip.octets()[i] & V4_MASK[i]).to_vec(),
+        IpAddr::V6(ip) => std::array::from_fn::<u8, 8, _>(|i| ip.octets()[i] & V6_MASK[i]).to_vec(),
+    };
+    bytes[0] |= (rand & 0x07) << 5;
+    crc32c(&bytes)
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test vectors of BEP 42: address, random byte, and the prefix of the id
+    const VECTORS: [(&str, u8, [u8; 3]); 5] = [
+        ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+        ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+        ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+        ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+        ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+    ];
+
+    #[test]
+    fn ids_match_the_test_vectors() {
+        for (ip, rand, prefix) in VECTORS {
+            let ip: IpAddr = ip.parse().unwrap();
+            let crc = crc(ip, rand);
+            assert_eq!([(crc >> 24) as u8, (crc >> 16) as u8], prefix[..2], "{ip}");
+            assert_eq!((crc >> 8) as u8 & 0xf8, prefix[2] & 0xf8, "{ip}");
+
+            let mut id = [0; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = rand;
+            assert!(is_compliant(&id, ip), "{ip}");
+            // Only the 21 leading bits are bound to the address
+            id[2] ^= 0x07;
+            assert!(is_compliant(&id, ip), "{ip}");
+            id[2] ^= 0x08;
+            assert!(!is_compliant(&id, ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn generated_ids_are_compliant() {
+        for ip in ["124.31.75.21", "2001:db8::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            let id = node_id(ip);
+            assert!(is_compliant(&id, ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn local_nodes_are_exempt() {
+        for ip in ["192.168.1.2", "10.0.0.1", "127.0.0.1", "::1", "fd00::1", "fe80::1"] {
+            assert!(is_compliant(&[0; 20], ip.parse().unwrap()), "{ip}");
+        }
+        assert!(!is_compliant(&[0; 20], "124.31.75.21".parse().unwrap()));
+    }
+}
@@ -0,0 +1,1310 @@
+use crate::bitfield::Bitfield;
+use crate::choker::{self, Candidate, Choker};
+use crate::message::{Message, MessageFramer};
+use crate::net::{self, HandShake, HANDSHAKE_TIMEOUT};
+use crate::peer::{PeerEvent, PeerState};
+use crate::pex::{self, PexEvent, PexState};
+use crate::picker::{self, PiecePlan};
+use crate::ratelimit::{Direction, PeerBandwidth, TorrentBandwidth};
+use crate::resume::{self, Progress, TrackerStats};
+use crate::session::NewPeer;
+use crate::storage::Storage;
+use crate::Torrent;
+use anyhow::Context;
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+pub const BLOCK_MAX: usize = 1 << 14;
+/// Largest block peers may request from us, as accepted by common clients. Larger ones would not
+/// fit in the frames of our framer anyway.
+const UPLOAD_MAX: usize = BLOCK_MAX;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time after which a peer not delivering a requested block is considered snubbing us
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time after which a silent peer is dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Peers learnt through peer exchange queued before further ones get dropped
+const EXCHANGED_BACKLOG: usize = 64;
+/// Most peer connections of a download, incoming ones included
+const MAX_CONNECTIONS: usize = 50;
+/// Peers from the tracker, the DHT or peer exchange waiting for a connection slot before further
+/// ones get dropped
+const MAX_WAITING: usize = 500;
+/// Time a download without any peer waits for new ones, from the tracker, the DHT or connecting
+/// to us, before giving up
+const PEERLESS_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Interval between saves of the resume file
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Score under which a peer is not connected to anymore, each timeout costing a point
+const MIN_SCORE: i32 = -3;
+
+/// A peer failed to answer in time
+#[derive(Debug, thiserror::Error)]
+#[error("{0} timed out")]
+struct TimedOut(&'static str);
+
+/// A block of a piece, as carried by `Request`, `Piece` and `Cancel` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    Requested(Vec<usize>), // Ids of the peers the block was requested from
+    Received,
+}
+
+struct PieceProgress {
+    index: usize,
+    plan: PiecePlan,
+    size: usize,
+    hash: [u8; 20],
+    blocks: Vec<BlockState>,
+    missing: usize,   // Blocks neither requested nor received
+    requested: usize, // Blocks requested and not received yet
+    writing: usize,   // Received blocks still being written to storage
+}
+
+impl PieceProgress {
+    fn new(plan: PiecePlan, size: usize, hash: [u8; 20]) -> Self {
+        let nblocks = usize::div_ceil(size, BLOCK_MAX); // Ceil for the potentially truncated block
+        Self {
+            index: plan.index,
+            plan,
+            size,
+            hash,
+            blocks: vec![BlockState::Missing; nblocks],
+            missing: nblocks,
+            requested: 0,
+            writing: 0,
+        }
+    }
+
+    /// Change the state of a block, keeping count of the missing and requested ones
+    fn replace(&mut self, block_i: usize, state: BlockState) -> BlockState {
+        match &state {
+            BlockState::Missing => self.missing += 1,
+            BlockState::Requested(_) => self.requested += 1,
+            BlockState::Received => {}
+        }
+        let previous = std::mem::replace(&mut self.blocks[block_i], state);
+        match &previous {
+            BlockState::Missing => self.missing -= 1,
+            BlockState::Requested(_) => self.requested -= 1,
+            BlockState::Received => {}
+        }
+        previous
+    }
+
+    /// Give back a block requested from a peer, missing again once requested from no other one
+    fn unrequest(&mut self, block_i: usize, peer: usize) {
+        if let BlockState::Requested(peers) = &mut self.blocks[block_i] {
+            peers.retain(|&p| p != peer);
+            if peers.is_empty() {
+                self.replace(block_i, BlockState::Missing);
+            }
+        }
+    }
+
+    fn block(&self, block_i: usize) -> Block {
+        let begin = block_i * BLOCK_MAX;
+        let length = usize::min(BLOCK_MAX, self.size - begin);
+        Block {
+            piece: self.index as u32,
+            begin: begin as u32,
+            length: length as u32,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.missing == 0 && self.requested == 0
+    }
+}
+
+/// What a peer task gets from the download when registering
+struct Registration {
+    id: usize,
+    cancels: mpsc::UnboundedReceiver<Block>, // Blocks to cancel at the peer
+    wake: Arc<Notify>,
+    storage: Arc<Storage>,
+}
+
+/// Shared state of a download, driven by one task per peer.
+///
+/// Blocks are handed out to peers once each. When every remaining block has been requested, the
+/// download enters endgame mode: outstanding blocks are requested again from every other peer
+/// having them, and as soon as one copy arrives a `Cancel` is sent to the others.
+///
+/// Blocks are written in place to `storage` as they arrive, and pieces are read back from it to be
+/// verified once all their blocks are written.
+pub struct Download {
+    storage: Arc<Storage>,
+    pending: Vec<PieceProgress>,
+    have: Bitfield, // Pieces we own
+    peers: HashMap<usize, mpsc::UnboundedSender<Block>>, // Cancel channel of each peer task
+    wake: Arc<Notify>, // Wakes idle peer tasks when blocks become available to them
+    next_peer: usize,
+    scores: HashMap<SocketAddr, i32>, // Timeouts cost points, delivered blocks earn some back
+    announced: HashMap<usize, Bitfield>, // Pieces announced by each peer
+    availability: Vec<usize>, // Number of peers having each piece
+    readahead: usize, // Pieces of sequential files picked before anything else
+    sequential: BTreeSet<usize>, // Pending pieces of sequential files, the first ones making the readahead window
+    urgent: BTreeSet<usize>, // Pieces waited for by readers, picked before anything else
+    verified: Arc<Notify>, // Wakes readers waiting for pieces
+    stopped: bool, // Whether `run` returned, pieces not verified by then never being
+    stats: TrackerStats,
+    endgame: bool,
+    seeding: bool, // Whether peers are kept once the download is complete, to upload to them
+    choker: Choker,
+    transfers: HashMap<usize, Transfer>, // What the choker knows of each peer
+    super_seed: Option<SuperSeed>,
+    listening: HashMap<usize, (SocketAddr, u8)>, // Address each peer listens on, with its peer exchange flags
+}
+
+/// Super-seeding state (BEP 16): pieces are revealed to peers one at a time instead of announcing
+/// them all, so that each piece we upload is one the swarm lacks
+#[derive(Debug, Default)]
+struct SuperSeed {
+    revealed: HashMap<usize, Vec<usize>>, // Pieces revealed to each peer, the last being pending
+    offers: Vec<usize>,                   // Number of peers each piece was revealed to
+}
+
+/// Activity of a peer during the current choking round
+#[derive(Debug, Clone, Copy, Default)]
+struct Transfer {
+    candidate: Candidate,
+    unchoked: bool,
+}
+
+impl Download {
+    pub fn new(torrent: &Torrent, storage: Arc<Storage>, pieces: impl IntoIterator<Item = impl Into<PiecePlan>>) -> Self {
+        let mut download = Self {
+            storage,
+            pending: Vec::new(),
+            have: Bitfield::new(torrent.info.pieces.0.len()),
+            peers: HashMap::new(),
+            wake: Arc::new(Notify::new()),
+            next_peer: 0,
+            scores: HashMap::new(),
+            announced: HashMap::new(),
+            availability: vec![0; torrent.info.pieces.0.len()],
+            readahead: 0,
+            sequential: BTreeSet::new(),
+            urgent: BTreeSet::new(),
+            verified: Arc::new(Notify::new()),
+            stopped: false,
+            stats: TrackerStats::default(),
+            endgame: false,
+            seeding: false,
+            choker: Choker::default(),
+            transfers: HashMap::new(),
+            super_seed: None,
+            listening: HashMap::new(),
+        };
+        for plan in pieces {
+            let plan = plan.into();
+            let size = download.storage.piece_size(plan.index);
+            let hash = *torrent.info.pieces.at(plan.index).expect("piece of the torrent");
+            if plan.sequential {
+                download.sequential.insert(plan.index);
+            }
+            download.pending.push(PieceProgress::new(plan, size, hash));
+        }
+        download
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Keep uploading to peers once the download is complete, until interrupted
+    pub fn set_seeding(&mut self, seeding: bool) {
+        self.seeding = seeding;
+    }
+
+    /// Hide our pieces and reveal them one at a time to each peer, until they propagate. Only
+    /// meaningful when seeding. With a single peer, nothing ever shows a revealed piece propagated:
+    /// that peer is left with the first piece, by design, rather than uploaded everything.
+    pub fn set_super_seeding(&mut self, super_seeding: bool) {
+        self.super_seed = super_seeding.then(|| SuperSeed {
+            offers: vec![0; self.have.piece_count()],
+            ..SuperSeed::default()
+        });
+    }
+
+    /// Whether peer connections are still useful, to download or to seed
+    fn keeps_peers(&self) -> bool {
+        self.seeding || !self.is_complete()
+    }
+
+    /// Number of pieces of sequential files to download ahead of everything else, in order
+    pub fn set_readahead(&mut self, pieces: usize) {
+        self.readahead = pieces;
+    }
+
+    /// Skip the pieces and blocks already on disk according to `progress`
+    pub fn restore(&mut self, progress: &Progress) {
+        self.have = progress.have.clone();
+        self.stats = progress.stats;
+        self.pending.retain(|p| !progress.have.has(p.index));
+        self.sequential.retain(|&index| !progress.have.has(index));
+        for (index, blocks) in &progress.partial {
+            if let Some(piece) = self.pending.iter_mut().find(|p| p.index == *index) {
+                for (block_i, _) in blocks.iter().enumerate().filter(|(_, &written)| written) {
+                    piece.replace(block_i, BlockState::Received);
+                }
+            }
+        }
+    }
+
+    /// Snapshot of the progress, to be saved in the resume file
+    pub fn progress(&self) -> Progress {
+        let partial = self
+            .pending
+            .iter()
+            // Pieces being written may have blocks half-written on disk
+            .filter(|p| p.writing == 0 && p.blocks.contains(&BlockState::Received))
+            .map(|p| (p.index, p.blocks.iter().map(|s| *s == BlockState::Received).collect()))
+            .collect();
+        Progress {
+            have: self.have.clone(),
+            partial,
+            stats: self.stats,
+        }
+    }
+
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    /// Whether `piece` is had or to be downloaded
+    pub fn planned(&self, piece: usize) -> bool {
+        self.have.has(piece) || self.pending.iter().any(|p| p.index == piece)
+    }
+
+    /// Pick the given pieces before anything else, as readers wait for them
+    pub fn prioritize(&mut self, pieces: impl IntoIterator<Item = usize>) {
+        let pending: Vec<usize> = pieces
+            .into_iter()
+            .filter(|&piece| self.pending.iter().any(|p| p.index == piece))
+            .collect();
+        if !pending.is_empty() {
+            self.urgent.extend(pending);
+            self.wake.notify_waiters();
+        }
+    }
+
+    pub fn stats(&self) -> TrackerStats {
+        self.stats
+    }
+
+    /// Number of bytes of the wanted pieces we do not have yet
+    pub fn left(&self) -> usize {
+        self.pending.iter().map(|p| p.size).sum()
+    }
+
+    /// Register a new peer task
+    fn register(&mut self) -> Registration {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = self.next_peer;
+        self.next_peer += 1;
+        self.peers.insert(id, tx);
+        self.transfers.insert(id, Transfer { candidate: Candidate { id, ..Candidate::default() }, unchoked: false });
+        Registration {
+            id,
+            cancels: rx,
+            wake: Arc::clone(&self.wake),
+            storage: Arc::clone(&self.storage),
+        }
+    }
+
+    /// Forget about a peer, giving its outstanding blocks back to the others.
+    fn release(&mut self, peer: usize) {
+        self.peers.remove(&peer);
+        self.transfers.remove(&peer);
+        self.listening.remove(&peer);
+        if let Some(super_seed) = &mut self.super_seed {
+            super_seed.revealed.remove(&peer);
+        }
+        if let Some(announced) = self.announced.remove(&peer) {
+            for (index, availability) in self.availability.iter_mut().enumerate() {
+                if announced.has(index) {
+                    *availability -= 1;
+                }
+            }
+        }
+        for progress in self.pending.iter_mut().filter(|p| p.requested > 0) {
+            for block_i in 0..progress.blocks.len() {
+                progress.unrequest(block_i, peer);
+            }
+        }
+        self.wake.notify_waiters();
+    }
+
+    /// Give back a single block requested from a peer, e.g. after a cancellation or a choke.
+    fn unrequest(&mut self, peer: usize, block: Block) {
+        if let Some(progress) = self.pending.iter_mut().find(|p| p.index == block.piece as usize) {
+            let block_i = block.begin as usize / BLOCK_MAX;
+            if block_i < progress.blocks.len() {
+                progress.unrequest(block_i, peer);
+            }
+        }
+        self.wake.notify_waiters();
+    }
+
+    /// Record the address a peer listens on, as connected to or told in its extension handshake,
+    /// the first one known being kept
+    fn listening(&mut self, peer: usize, addr: SocketAddr, flags: u8) {
+        self.listening.entry(peer).or_insert((addr, 0)).1 |= flags;
+    }
+
+    /// Peers to tell others about through peer exchange, with their flags, but `peer` itself
+    fn swarm(&self, peer: usize) -> HashMap<SocketAddr, u8> {
+        self.listening
+            .iter()
+            .filter(|&(&id, _)| id != peer)
+            .map(|(id, &(addr, flags))| {
+                let seed = self.announced.get(id).is_some_and(|announced| announced.is_complete());
+                (addr, if seed { flags | pex::SEED } else { flags })
+            })
+            .collect()
+    }
+
+    /// Whether we already exchange with the peer listening on `addr`
+    fn knows(&self, addr: SocketAddr) -> bool {
+        self.listening.values().any(|&(listening, _)| listening == addr)
+    }
+
+    /// Lower the score of a peer after a timeout, returning whether it is still worth keeping
+    fn penalize(&mut self, addr: SocketAddr) -> bool {
+        let score = self.scores.entry(addr).or_insert(0);
+        *score -= 1;
+        *score > MIN_SCORE
+    }
+
+    fn reward(&mut self, addr: SocketAddr) {
+        let score = self.scores.entry(addr).or_insert(0);
+        *score = i32::min(*score + 1, 0);
+    }
+
+    /// Give back the blocks whose requests a peer let time out, penalizing it. Returns whether
+    /// the peer is still worth keeping.
+    fn timed_out(&mut self, peer: usize, addr: SocketAddr, blocks: &[Block]) -> bool {
+        for &block in blocks {
+            self.unrequest(peer, block);
+        }
+        self.penalize(addr)
+    }
+
+    /// Delay before connecting again to a peer whose connection ended with `error`, if worth it:
+    /// only peers timing out are, until they have exhausted their score
+    fn reconnect_delay(&mut self, addr: SocketAddr, error: &anyhow::Error) -> Option<Duration> {
+        (error.downcast_ref::<TimedOut>().is_some() && self.penalize(addr)).then_some(RECONNECT_DELAY)
+    }
+
+    /// Record pieces newly announced by a peer
+    fn announced(&mut self, peer: usize, pieces: &[usize]) -> anyhow::Result<()> {
+        let npieces = self.availability.len();
+        let announced = self.announced.entry(peer).or_insert_with(|| Bitfield::new(npieces));
+        for &index in pieces {
+            if !announced.has(index) {
+                announced.set(index)?;
+                self.availability[index] += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record pieces a peer does not announce anymore, as its later bitfield lacks them
+    fn withdrawn(&mut self, peer: usize, pieces: &[usize]) -> anyhow::Result<()> {
+        let Some(announced) = self.announced.get_mut(&peer) else {
+            return Ok(());
+        };
+        for &index in pieces {
+            if announced.has(index) {
+                announced.clear(index)?;
+                self.availability[index] -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a peer may request `block` from us: it must be within a piece we have, and
+    /// revealed to the peer when super-seeding
+    fn uploadable(&self, peer: usize, block: Block) -> bool {
+        let (piece, begin, length) = (block.piece as usize, block.begin as usize, block.length as usize);
+        let revealed = self.super_seed.as_ref().is_none_or(|super_seed| {
+            super_seed.revealed.get(&peer).is_some_and(|pieces| pieces.contains(&piece))
+        });
+        self.have.has(piece)
+            && revealed
+            && (1..=UPLOAD_MAX).contains(&length)
+            && begin + length <= self.storage.piece_size(piece)
+    }
+
+    /// Pieces to advertise to a peer when connecting to it, none when super-seeding
+    fn advertised(&self) -> Option<Bitfield> {
+        (self.super_seed.is_none() && self.have.count() > 0).then(|| self.have.clone())
+    }
+
+    /// Pieces to announce with `Have` to a peer, given those already announced to it and those it
+    /// owns. When super-seeding, a single piece the peer lacks is revealed, the rarest and least
+    /// offered one, and the next one only once another peer announced the previous one.
+    fn next_haves(&mut self, peer: usize, announced: &Bitfield, owned: &Bitfield) -> Vec<usize> {
+        let Some(super_seed) = &mut self.super_seed else {
+            if self.have.count() == announced.count() {
+                return Vec::new();
+            }
+            return announced.iter_missing().filter(|&i| self.have.has(i)).collect();
+        };
+        let revealed = super_seed.revealed.entry(peer).or_default();
+        if let Some(&pending) = revealed.last() {
+            let propagated = self.announced.iter().any(|(&other, pieces)| other != peer && pieces.has(pending));
+            if !propagated {
+                return Vec::new();
+            }
+        }
+        let next = owned
+            .iter_missing()
+            .filter(|&i| self.have.has(i) && !revealed.contains(&i))
+            .min_by_key(|&i| (self.availability[i], super_seed.offers[i], i));
+        if let Some(piece) = next {
+            revealed.push(piece);
+            super_seed.offers[piece] += 1;
+        }
+        next.into_iter().collect()
+    }
+
+    fn uploaded(&mut self, peer: usize, bytes: usize) {
+        self.stats.uploaded += bytes;
+        if let Some(transfer) = self.transfers.get_mut(&peer) {
+            transfer.candidate.uploaded += bytes;
+        }
+    }
+
+    /// Update what the choker knows of a peer. Free unchoke slots are handed out to newly
+    /// interested peers right away rather than at the next round.
+    fn report(&mut self, peer: usize, interested: bool, snubbed: bool) {
+        let free = self.transfers.values().filter(|t| t.unchoked).count() < choker::UNCHOKE_SLOTS;
+        if let Some(transfer) = self.transfers.get_mut(&peer) {
+            if interested && !transfer.candidate.interested && free {
+                transfer.unchoked = true;
+            }
+            transfer.candidate.interested = interested;
+            transfer.candidate.snubbed = snubbed;
+        }
+    }
+
+    fn unchoked(&self, peer: usize) -> bool {
+        self.transfers.get(&peer).is_some_and(|t| t.unchoked)
+    }
+
+    /// Run a round of the choker over every peer, starting a new round of transfer accounting
+    fn rechoke(&mut self) {
+        let candidates: Vec<Candidate> = self.transfers.values().map(|t| t.candidate).collect();
+        let unchoked = self.choker.rechoke(&candidates, self.is_complete());
+        for (id, transfer) in &mut self.transfers {
+            transfer.unchoked = unchoked.contains(id);
+            transfer.candidate.downloaded = 0;
+            transfer.candidate.uploaded = 0;
+        }
+        self.wake.notify_waiters();
+    }
+
+    /// Whether the peer owning `bitfield` has pieces we still need
+    fn wants(&self, bitfield: &Bitfield) -> bool {
+        self.pending.iter().any(|p| bitfield.has(p.index))
+    }
+
+    /// Pick the next block to request from a peer owning the pieces for which `has` holds.
+    fn next_request(&mut self, peer: usize, has: impl Fn(usize) -> bool) -> Option<Block> {
+        // The readahead window is made of the first pieces of sequential files still pending
+        let window_end = self.sequential.iter().take(self.readahead).next_back().copied();
+
+        let best = self
+            .pending
+            .iter_mut()
+            .filter(|p| has(p.index) && p.missing > 0)
+            .min_by_key(|p| {
+                let in_window = p.plan.sequential && window_end.is_some_and(|end| p.index <= end);
+                let urgent = in_window || self.urgent.contains(&p.index);
+                picker::rank(&p.plan, urgent, self.availability[p.index])
+            });
+        if let Some(progress) = best {
+            let block_i = progress
+                .blocks
+                .iter()
+                .position(|s| *s == BlockState::Missing)
+                .expect("piece with a missing block");
+            progress.replace(block_i, BlockState::Requested(vec![peer]));
+            return Some(progress.block(block_i));
+        }
+
+        // Nothing left to hand out to this peer: if every block of the download is requested or
+        // received, race the slow peers for the outstanding ones.
+        if self.pending.iter().any(|p| p.missing > 0) {
+            return None;
+        }
+        if !self.endgame {
+            eprintln!("Entering endgame mode");
+            self.endgame = true;
+            self.wake.notify_waiters();
+        }
+        for progress in self.pending.iter_mut().filter(|p| has(p.index) && p.requested > 0) {
+            for (block_i, state) in progress.blocks.iter_mut().enumerate() {
+                if let BlockState::Requested(peers) = state {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                        return Some(progress.block(block_i));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Record a block received from a peer, cancelling it at the other peers it was requested
+    /// from. Returns whether the block has to be written to storage, which is not the case of
+    /// late endgame duplicates.
+    fn received(&mut self, peer: usize, block: Block) -> anyhow::Result<bool> {
+        let Some(progress) = self.pending.iter_mut().find(|p| p.index == block.piece as usize) else {
+            return Ok(false); // Already verified
+        };
+        let block_i = block.begin as usize / BLOCK_MAX;
+        anyhow::ensure!(
+            block_i < progress.blocks.len() && progress.block(block_i) == block,
+            "peer sent a block of length {} at offset {} of piece {}",
+            block.length,
+            block.begin,
+            block.piece
+        );
+        match progress.replace(block_i, BlockState::Received) {
+            BlockState::Received => return Ok(false),
+            BlockState::Requested(peers) => {
+                for other in peers.into_iter().filter(|&p| p != peer) {
+                    if let Some(cancels) = self.peers.get(&other) {
+                        let _ = cancels.send(block);
+                    }
+                }
+            }
+            BlockState::Missing => {}
+        }
+        progress.writing += 1;
+        self.stats.downloaded += block.length as usize;
+        if let Some(transfer) = self.transfers.get_mut(&peer) {
+            transfer.candidate.downloaded += block.length as usize;
+        }
+        Ok(true)
+    }
+
+    /// Record that a received block is written to storage, or failed to be and is to be requested
+    /// again, returning the expected hash of its piece if the piece is now complete on disk and has
+    /// to be verified.
+    fn written(&mut self, block: Block, stored: bool) -> Option<[u8; 20]> {
+        let progress = self.pending.iter_mut().find(|p| p.index == block.piece as usize)?;
+        progress.writing -= 1;
+        if !stored {
+            progress.replace(block.begin as usize / BLOCK_MAX, BlockState::Missing);
+            self.wake.notify_waiters();
+        }
+        (progress.writing == 0 && progress.is_complete()).then_some(progress.hash)
+    }
+
+    /// Record the outcome of the hash check of a complete piece
+    fn verified(&mut self, piece: usize, valid: bool) -> anyhow::Result<()> {
+        let Some(pos) = self.pending.iter().position(|p| p.index == piece) else {
+            return Ok(());
+        };
+        if valid {
+            self.pending.swap_remove(pos);
+            self.sequential.remove(&piece);
+            self.urgent.remove(&piece);
+            self.have.set(piece)?;
+            self.verified.notify_waiters();
+            eprintln!("Piece {piece} verified ({}/{} pieces)", self.have.count(), self.have.piece_count());
+        } else {
+            eprintln!("Piece {piece} failed its hash check, retrying");
+            let progress = &mut self.pending[pos];
+            for block_i in 0..progress.blocks.len() {
+                progress.replace(block_i, BlockState::Missing);
+            }
+            self.wake.notify_waiters();
+        }
+        if !self.keeps_peers() {
+            // Dropping the cancel channels tells every peer task to stop
+            self.peers.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Write a block received from a peer to storage, verifying its piece if it completes it
+async fn store(download: &Mutex<Download>, storage: &Storage, peer: usize, block: Block, data: &[u8]) -> anyhow::Result<()> {
+    if !download.lock().expect("download lock poisoned").received(peer, block)? {
+        return Ok(());
+    }
+    let piece = block.piece as usize;
+    let result = storage.write_block(piece, block.begin as usize, data).await;
+    let expected = download.lock().expect("download lock poisoned").written(block, result.is_ok());
+    result?;
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+
+    let data = storage.read_piece(piece).await?;
+    let mut hasher = Sha1::new();
+    hasher.update(&data);
+    let hash: [u8; 20] = hasher.finalize().into();
+    download.lock().expect("download lock poisoned").verified(piece, hash == expected)
+}
+
+/// Wait until `piece` is verified, making it urgent meanwhile. Fails once the download stopped.
+pub async fn wait_for_piece(download: &Mutex<Download>, piece: usize) -> anyhow::Result<()> {
+    let verified = Arc::clone(&download.lock().expect("download lock poisoned").verified);
+    loop {
+        // Created before checking, so that a verification in between is not missed
+        let notified = verified.notified();
+        {
+            let mut download = download.lock().expect("download lock poisoned");
+            if download.have.has(piece) {
+                return Ok(());
+            }
+            anyhow::ensure!(download.planned(piece), "piece {piece} is not part of the download");
+            anyhow::ensure!(!download.stopped, "download stopped before piece {piece}");
+            download.prioritize([piece]);
+        }
+        notified.await;
+    }
+}
+
+/// Wait until every wanted piece is verified
+pub async fn wait_for_completion(download: &Mutex<Download>) {
+    let verified = Arc::clone(&download.lock().expect("download lock poisoned").verified);
+    loop {
+        // Created before checking, so that a verification in between is not missed
+        let notified = verified.notified();
+        if download.lock().expect("download lock poisoned").is_complete() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Download from every given peer, and those connecting to us through `incoming`, until
+/// `download` is complete. At most [`MAX_CONNECTIONS`] peers are connected at a time, the others
+/// waiting for a connection to end.
+///
+/// Unless the torrent is private, more peers are learnt from the connected ones through peer
+/// exchange, which are told we listen on `port`.
+///
+/// When given a resume file, the progress is saved to it periodically, at the end of the download
+/// and when interrupted with Ctrl-C. A seeding download goes on until interrupted, others give up
+/// when left without peers for a while.
+pub async fn run(
+    download: Arc<Mutex<Download>>,
+    torrent: &Torrent,
+    peers: &[SocketAddrV4],
+    incoming: Option<mpsc::Receiver<NewPeer>>,
+    port: u16,
+    bandwidth: TorrentBandwidth,
+    resume: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let result = transfer(Arc::clone(&download), torrent, peers, incoming, port, bandwidth, resume).await;
+    let mut download = download.lock().expect("download lock poisoned");
+    download.stopped = true;
+    download.verified.notify_waiters(); // Readers waiting for pieces fail
+    result
+}
+
+async fn transfer(
+    download: Arc<Mutex<Download>>,
+    torrent: &Torrent,
+    peers: &[SocketAddrV4],
+    mut incoming: Option<mpsc::Receiver<NewPeer>>,
+    port: u16,
+    bandwidth: TorrentBandwidth,
+    resume: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let info_hash = torrent.info_hash();
+    let npieces = download.lock().expect("download lock poisoned").have.piece_count();
+    let (found, mut exchanged) = mpsc::channel(EXCHANGED_BACKLOG);
+    let pex = (!torrent.is_private()).then(|| Pex { port, found: found.clone() });
+    let mut tasks = JoinSet::new();
+    let mut connected = HashSet::new(); // Peers we run an outgoing connection to, or will
+    let mut waiting = VecDeque::new(); // Peers to connect to once there is room
+    for &addr in peers {
+        let addr = SocketAddr::V4(addr);
+        if waiting.len() < MAX_WAITING && connected.insert(addr) {
+            waiting.push_back(addr);
+        }
+    }
+
+    let mut save = tokio::time::interval_at((Instant::now() + SAVE_INTERVAL).into(), SAVE_INTERVAL);
+    let mut rechoke = tokio::time::interval(choker::ROUND);
+    let mut interrupted = false;
+    let mut give_up = None; // When a download left without peers stops waiting for new ones
+    loop {
+        while tasks.len() < MAX_CONNECTIONS {
+            let Some(addr) = waiting.pop_front() else {
+                break;
+            };
+            connect_to(&mut tasks, addr, info_hash, npieces, &download, &bandwidth, &pex);
+        }
+        // Without peers left, keep waiting for incoming ones unless there is nothing left to get
+        if tasks.is_empty()
+            && (incoming.is_none() || !download.lock().expect("download lock poisoned").keeps_peers())
+        {
+            break;
+        }
+        if tasks.is_empty() && !download.lock().expect("download lock poisoned").seeding {
+            give_up.get_or_insert_with(|| Instant::now() + PEERLESS_TIMEOUT);
+        } else {
+            give_up = None;
+        }
+        tokio::select! {
+            Some(joined) = tasks.join_next(), if !tasks.is_empty() => {
+                if let Some(addr) = joined.context("join peer task")? {
+                    connected.remove(&addr);
+                }
+            }
+            peer = async { incoming.as_mut()?.recv().await }, if incoming.is_some() => {
+                let Some(peer) = peer else {
+                    incoming = None; // Not listening anymore
+                    continue;
+                };
+                if !download.lock().expect("download lock poisoned").keeps_peers() {
+                    continue;
+                }
+                match peer {
+                    NewPeer::Incoming { addr, .. } if tasks.len() >= MAX_CONNECTIONS => {
+                        eprintln!("Peer {addr}: refused, {MAX_CONNECTIONS} peers connected already");
+                    }
+                    NewPeer::Incoming { stream, addr, handshake } => {
+                        let download = Arc::clone(&download);
+                        let bandwidth = bandwidth.clone();
+                        let pex = pex.clone();
+                        tasks.spawn(async move {
+                            let stream = Some((stream, handshake));
+                            if let Err(e) = run_peer(addr, stream, info_hash, npieces, &download, &bandwidth, &pex).await {
+                                eprintln!("Peer {addr}: {e:#}");
+                            }
+                            None
+                        });
+                    }
+                    NewPeer::Discovered(addr) => {
+                        if waiting.len() < MAX_WAITING && connected.insert(addr) {
+                            waiting.push_back(addr);
+                        }
+                    }
+                }
+            }
+            Some(addr) = exchanged.recv() => {
+                if download.lock().expect("download lock poisoned").keeps_peers()
+                    && waiting.len() < MAX_WAITING
+                    && connected.insert(addr)
+                {
+                    waiting.push_back(addr);
+                }
+            }
+            _ = tokio::time::sleep_until(give_up.unwrap_or_else(Instant::now).into()), if give_up.is_some() => break,
+            _ = rechoke.tick() => download.lock().expect("download lock poisoned").rechoke(),
+            _ = save.tick(), if resume.is_some() => {
+                if let Err(e) = save_progress(&download, torrent, resume.as_deref()).await {
+                    eprintln!("Saving resume file: {e:#}");
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                // Stop every write before saving, for the file stamps to match what is on disk
+                tasks.shutdown().await;
+                interrupted = true;
+                break;
+            }
+        }
+    }
+    save_progress(&download, torrent, resume.as_deref()).await?;
+    let complete = download.lock().expect("download lock poisoned").is_complete();
+    anyhow::ensure!(!interrupted || complete, "Interrupted, progress saved");
+    anyhow::ensure!(
+        complete,
+        "every peer disconnected before the end of the download"
+    );
+    Ok(())
+}
+
+/// What peer tasks need for peer exchange, which private torrents go without
+#[derive(Debug, Clone)]
+struct Pex {
+    port: u16, // Port we listen on
+    found: mpsc::Sender<SocketAddr>, // Peers learnt from others, to connect to
+}
+
+/// Spawn a task connecting to a peer, and reconnecting as long as it only times out
+fn connect_to(
+    tasks: &mut JoinSet<Option<SocketAddr>>,
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    npieces: usize,
+    download: &Arc<Mutex<Download>>,
+    bandwidth: &TorrentBandwidth,
+    pex: &Option<Pex>,
+) {
+    let download = Arc::clone(download);
+    let bandwidth = bandwidth.clone();
+    let pex = pex.clone();
+    tasks.spawn(async move {
+        while download.lock().expect("download lock poisoned").keeps_peers() {
+            let Err(e) = run_peer(addr, None, info_hash, npieces, &download, &bandwidth, &pex).await else {
+                break;
+            };
+            eprintln!("Peer {addr}: {e:#}");
+            let Some(delay) = download.lock().expect("download lock poisoned").reconnect_delay(addr, &e) else {
+                break;
+            };
+            tokio::time::sleep(delay).await;
+        }
+        Some(addr)
+    });
+}
+
+async fn save_progress(download: &Mutex<Download>, torrent: &Torrent, path: Option<&std::path::Path>) -> anyhow::Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let (progress, storage) = {
+        let download = download.lock().expect("download lock poisoned");
+        (download.progress(), Arc::clone(&download.storage))
+    };
+    resume::save(torrent, &storage, path, &progress).await
+}
+
+/// Exchange with a peer until it disconnects or the download completes, connecting to it unless
+/// it connected to us and is already handshaken
+async fn run_peer(
+    addr: SocketAddr,
+    stream: Option<(TcpStream, HandShake)>,
+    info_hash: [u8; 20],
+    npieces: usize,
+    download: &Mutex<Download>,
+    bandwidth: &TorrentBandwidth,
+    pex: &Option<Pex>,
+) -> anyhow::Result<()> {
+    let outgoing = stream.is_none();
+    let (stream, handshake) = match stream {
+        Some(stream) => stream,
+        None => connect(addr, info_hash).await?,
+    };
+    let mut registration = download.lock().expect("download lock poisoned").register();
+    if outgoing {
+        download.lock().expect("download lock poisoned").listening(registration.id, addr, pex::REACHABLE);
+    }
+    let pex = pex.as_ref().filter(|_| handshake.supports_extensions());
+    let result = exchange(stream, addr, npieces, &mut registration, download, bandwidth.peer(), pex).await;
+    download.lock().expect("download lock poisoned").release(registration.id);
+    result
+}
+
+async fn connect(addr: SocketAddr, info_hash: [u8; 20]) -> anyhow::Result<(TcpStream, HandShake)> {
+    let mut peer = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| TimedOut("TCP connection to peer"))?
+        .context("TCP connection to peer")?;
+
+    let handshake = timeout(HANDSHAKE_TIMEOUT, net::handshake(&mut peer, info_hash))
+        .await
+        .map_err(|_| TimedOut("handshake"))??;
+    Ok((peer, handshake))
+}
+
+async fn exchange(
+    peer: TcpStream,
+    addr: SocketAddr,
+    npieces: usize,
+    registration: &mut Registration,
+    download: &Mutex<Download>,
+    bandwidth: PeerBandwidth,
+    pex: Option<&Pex>,
+) -> anyhow::Result<()> {
+    let Registration { id, cancels, wake, storage } = registration;
+    let id = *id;
+    let mut peer = Framed::new(peer, MessageFramer::for_pieces(npieces));
+
+    // Tell the peer what we can upload, then announce pieces as they get verified
+    let mut announced = Bitfield::new(npieces);
+    let advertised = download.lock().expect("download lock poisoned").advertised();
+    if let Some(advertised) = advertised {
+        send(&mut peer, &bandwidth, Message::Bitfield(advertised.clone())).await.context("Send bitfield")?;
+        announced = advertised;
+    }
+
+    // Offer peer exchange to peers supporting the extension protocol
+    let mut exchanged = PexState::default();
+    if let Some(pex) = pex {
+        send(&mut peer, &bandwidth, pex::handshake(pex.port)?).await.context("Send extension handshake")?;
+    }
+    let mut exchange = tokio::time::interval_at((Instant::now() + pex::INTERVAL).into(), pex::INTERVAL);
+
+    let mut state = PeerState::new(npieces);
+    let mut last_message = Instant::now();
+    let mut keep_alive = tokio::time::interval_at((Instant::now() + KEEP_ALIVE_INTERVAL).into(), KEEP_ALIVE_INTERVAL);
+    let mut check = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            message = peer.next() => {
+                let Some(message) = message else {
+                    return Ok(()); // Peer closed the connection
+                };
+                let message = message.context("Invalid message from peer")?;
+                // Only accounted for once read, so the rate may be overshot by a frame per peer,
+                // the waiting peer being throttled by TCP flow control in the meantime
+                bandwidth.acquire(Direction::Download, message.wire_len()).await;
+                last_message = Instant::now();
+                match state.handle(message)? {
+                    Some(PeerEvent::Choked { dropped }) => {
+                        // The peer discards our pending requests on choke, give them back so that
+                        // they get requested again, from this peer once it unchokes or another one
+                        eprintln!("Peer {addr} choked us, {} requests dropped", dropped.len());
+                        let mut download = download.lock().expect("download lock poisoned");
+                        for block in dropped {
+                            download.unrequest(id, block);
+                        }
+                    }
+                    Some(PeerEvent::Unchoked) => eprintln!("Peer {addr} unchoked us"),
+                    Some(PeerEvent::Have(piece)) => {
+                        download.lock().expect("download lock poisoned").announced(id, &[piece])?;
+                    }
+                    Some(PeerEvent::Bitfield { added, dropped }) => {
+                        let mut download = download.lock().expect("download lock poisoned");
+                        download.announced(id, &added)?;
+                        download.withdrawn(id, &dropped)?;
+                    }
+                    Some(PeerEvent::Block { block, data }) => {
+                        anyhow::ensure!(data.len() == block.length as usize, "peer sent a block of the wrong length");
+                        download.lock().expect("download lock poisoned").reward(addr);
+                        store(download, storage, id, block, &data).await?;
+                    }
+                    Some(PeerEvent::Requested(block)) => {
+                        anyhow::ensure!(
+                            download.lock().expect("download lock poisoned").uploadable(id, block),
+                            "peer requested {} bytes at offset {} of piece {}, which we cannot send",
+                            block.length,
+                            block.begin,
+                            block.piece
+                        );
+                    }
+                    Some(PeerEvent::Extended { id: message_id, payload }) => {
+                        let event = exchanged.handle(message_id, &payload).context("Invalid extended message from peer")?;
+                        match (event, pex) {
+                            (Some(PexEvent::Handshake { port: Some(port), flags }), _) => {
+                                let listening = SocketAddr::new(addr.ip(), port);
+                                download.lock().expect("download lock poisoned").listening(id, listening, flags);
+                            }
+                            // Peers are only taken from those we offered peer exchange to
+                            (Some(PexEvent::Added(peers)), Some(pex)) => {
+                                let download = download.lock().expect("download lock poisoned");
+                                let seed = download.have.is_complete();
+                                for (addr, flags) in peers {
+                                    // Seeds have nothing to give each other
+                                    if download.knows(addr) || (seed && flags & pex::SEED != 0) {
+                                        continue;
+                                    }
+                                    let _ = pex.found.try_send(addr); // Dropped when flooded
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            cancel = cancels.recv() => {
+                let Some(block) = cancel else {
+                    return Ok(()); // Download complete
+                };
+                if let Some(cancel) = state.cancel(block) {
+                    send(&mut peer, &bandwidth, cancel).await.context("Send Cancel")?;
+                }
+            }
+            _ = wake.notified() => {}
+            _ = std::future::ready(()), if state.has_uploads() => {}
+            _ = exchange.tick(), if pex.is_some() => {
+                let swarm = download.lock().expect("download lock poisoned").swarm(id);
+                if let Some(message) = exchanged.next(&swarm)? {
+                    send(&mut peer, &bandwidth, message).await.context("Send peer exchange")?;
+                }
+            }
+            _ = keep_alive.tick() => {
+                send(&mut peer, &bandwidth, Message::KeepAlive).await.context("Send keep-alive")?;
+            }
+            _ = check.tick() => {
+                if last_message.elapsed() > IDLE_TIMEOUT {
+                    return Err(TimedOut("waiting for a message").into());
+                }
+                let expired = state.timed_out(REQUEST_TIMEOUT);
+                if !expired.is_empty() {
+                    eprintln!("Peer {addr} is snubbing us, {} requests timed out", expired.len());
+                    let mut download = download.lock().expect("download lock poisoned");
+                    anyhow::ensure!(download.timed_out(id, addr, &expired), "peer dropped after repeated timeouts");
+                }
+            }
+        }
+
+        // Serve one block per iteration, so that cancels get a chance to arrive in between
+        if let Some(block) = state.next_upload() {
+            let data = storage
+                .read_block(block.piece as usize, block.begin as usize, block.length as usize)
+                .await?;
+            send(&mut peer, &bandwidth, Message::Piece { index: block.piece, begin: block.begin, block: data })
+                .await
+                .context("Send block")?;
+            download.lock().expect("download lock poisoned").uploaded(id, block.length as usize);
+        }
+
+        let (wanted, haves, unchoked, seed) = {
+            let mut download = download.lock().expect("download lock poisoned");
+            download.report(id, state.peer_interested, state.am_interested && state.snubbed);
+            let haves = download.next_haves(id, &announced, &state.bitfield);
+            (download.wants(&state.bitfield), haves, download.unchoked(id), download.have.is_complete())
+        };
+        for index in haves {
+            send(&mut peer, &bandwidth, Message::Have(index as u32)).await.context("Send have")?;
+            announced.set(index)?;
+        }
+        if seed && state.bitfield.is_complete() {
+            return Ok(()); // Two seeds have nothing to exchange
+        }
+        if let Some(interest) = state.set_interested(wanted) {
+            send(&mut peer, &bandwidth, interest).await.context("Send interest")?;
+        }
+        if let Some(choke) = state.set_choking(!unchoked) {
+            send(&mut peer, &bandwidth, choke).await.context("Send choke")?;
+        }
+        while state.can_request() {
+            let block = download
+                .lock()
+                .expect("download lock poisoned")
+                .next_request(id, |i| state.bitfield.has(i));
+            let Some(block) = block else {
+                break;
+            };
+            send(&mut peer, &bandwidth, state.request(block))
+                .await
+                .context("Send block request")?;
+        }
+    }
+}
+
+/// Send a message to a peer once its bandwidth allows
+async fn send(
+    peer: &mut Framed<TcpStream, MessageFramer>,
+    bandwidth: &PeerBandwidth,
+    message: Message,
+) -> std::io::Result<()> {
+    bandwidth.acquire(Direction::Upload, message.wire_len()).await;
+    peer.send(message).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// A download of a torrent of a single piece of two blocks, stored under `root`
+    fn download(root: &std::path::Path) -> (Download, Vec<u8>) {
+        let (torrent, data) = testing::torrent(&[BLOCK_MAX + 100], 2 * BLOCK_MAX);
+        let storage = Arc::new(Storage::new(&torrent.info, root).unwrap());
+        (Download::new(&torrent, storage, [0]), data)
+    }
+
+    fn block(begin: usize, length: usize) -> Block {
+        Block { piece: 0, begin: begin as u32, length: length as u32 }
+    }
+
+    #[tokio::test]
+    async fn endgame_requests_the_last_blocks_twice() {
+        let dir = tempfile::tempdir().unwrap();
+        let (download, data) = download(dir.path());
+        let download = Mutex::new(download);
+        let (mut a, mut b) = {
+            let mut download = download.lock().unwrap();
+            (download.register(), download.register())
+        };
+        let (first, last) = (block(0, BLOCK_MAX), block(BLOCK_MAX, 100));
+        {
+            let mut download = download.lock().unwrap();
+            assert_eq!(download.next_request(a.id, |_| true), Some(first));
+            assert_eq!(download.next_request(b.id, |_| true), Some(last));
+            assert!(!download.endgame);
+            // Every block is requested: each peer races the other for its block
+            assert_eq!(download.next_request(a.id, |_| true), Some(last));
+            assert!(download.endgame);
+            assert_eq!(download.next_request(b.id, |_| true), Some(first));
+            assert_eq!(download.next_request(b.id, |_| true), None);
+        }
+
+        store(&download, &a.storage, a.id, last, &data[BLOCK_MAX..]).await.unwrap();
+        assert_eq!(b.cancels.try_recv().unwrap(), last);
+        assert!(a.cancels.try_recv().is_err());
+        // The late copy is dropped
+        assert!(!download.lock().unwrap().received(b.id, last).unwrap());
+
+        store(&download, &b.storage, b.id, first, &data[..BLOCK_MAX]).await.unwrap();
+        assert_eq!(a.cancels.try_recv().unwrap(), first);
+        assert!(download.lock().unwrap().is_complete());
+        assert_eq!(download.lock().unwrap().stats().downloaded, data.len());
+    }
+
+    #[tokio::test]
+    async fn blocks_failing_to_be_written_are_requested_again() {
+        // Files cannot be created under a regular file
+        let root = tempfile::NamedTempFile::new().unwrap();
+        let (download, data) = download(root.path());
+        let download = Mutex::new(download);
+        let peer = download.lock().unwrap().register();
+        let first = download.lock().unwrap().next_request(peer.id, |_| true).unwrap();
+
+        assert!(store(&download, &peer.storage, peer.id, first, &data[..BLOCK_MAX]).await.is_err());
+        let mut download = download.lock().unwrap();
+        assert_eq!(download.pending[0].missing, 2);
+        assert_eq!(download.next_request(peer.id, |_| true), Some(first));
+    }
+
+    #[test]
+    fn readahead_window_goes_first() {
+        let (torrent, _) = testing::torrent(&[6 * BLOCK_MAX], BLOCK_MAX);
+        let storage = Arc::new(Storage::new(&torrent.info, std::path::Path::new("/nonexistent")).unwrap());
+        let plan = |index, priority, sequential| PiecePlan { index, priority, sequential };
+        let (normal, high) = (picker::Priority::Normal, picker::Priority::High);
+        let plans = [
+            plan(0, normal, false),
+            plan(1, normal, false),
+            plan(2, normal, true),
+            plan(3, normal, true),
+            plan(4, normal, true),
+            plan(5, high, false),
+        ];
+        let mut download = Download::new(&torrent, storage, plans);
+        download.set_readahead(2);
+        let peer = download.register().id;
+        download.announced(peer, &[0, 1, 2, 3, 4, 5]).unwrap();
+        download.announced(peer + 1, &[1]).unwrap();
+        // The window before higher priorities, then sequential pieces in order as if the rarest
+        let order: Vec<u32> = (0..6).map(|_| download.next_request(peer, |_| true).unwrap().piece).collect();
+        assert_eq!(order, [2, 3, 5, 4, 0, 1]);
+    }
+
+    #[test]
+    fn super_seeding_reveals_a_piece_once_the_previous_one_spread() {
+        let (torrent, _) = testing::torrent(&[3 * BLOCK_MAX], BLOCK_MAX);
+        let storage = Arc::new(Storage::new(&torrent.info, std::path::Path::new("/nonexistent")).unwrap());
+        let mut download = Download::new(&torrent, storage, std::iter::empty::<usize>());
+        for piece in 0..3 {
+            download.have.set(piece).unwrap();
+        }
+        download.set_super_seeding(true);
+        assert_eq!(download.advertised(), None);
+        let (a, b) = (download.register().id, download.register().id);
+        let (none, mut owned) = (Bitfield::new(3), Bitfield::new(3));
+
+        assert_eq!(download.next_haves(a, &none, &none), [0]);
+        assert!(download.next_haves(a, &none, &none).is_empty());
+        // The peer itself announcing the piece does not show it spread
+        owned.set(0).unwrap();
+        download.announced(a, &[0]).unwrap();
+        assert!(download.next_haves(a, &none, &owned).is_empty());
+        // Another peer is offered a piece not offered yet
+        assert_eq!(download.next_haves(b, &none, &none), [1]);
+        assert!(download.next_haves(a, &none, &owned).is_empty());
+        download.announced(b, &[0]).unwrap();
+        assert_eq!(download.next_haves(a, &none, &owned), [2]);
+        assert!(!download.uploadable(a, Block { piece: 1, begin: 0, length: 16 }));
+        assert!(download.uploadable(a, Block { piece: 2, begin: 0, length: 16 }));
+    }
+
+    #[test]
+    fn availability_follows_replaced_bitfields() {
+        let (mut download, _) = download(std::path::Path::new("/nonexistent"));
+        let peer = download.register().id;
+        download.announced(peer, &[0]).unwrap();
+        assert_eq!(download.availability, [1]);
+        download.withdrawn(peer, &[0]).unwrap();
+        assert_eq!(download.availability, [0]);
+        download.withdrawn(peer, &[0]).unwrap();
+        download.announced(peer, &[0]).unwrap();
+        download.release(peer);
+        assert_eq!(download.availability, [0]);
+    }
+
+    #[test]
+    fn scores_fall_with_timeouts_and_recover_with_blocks() {
+        let (mut download, _) = download(std::path::Path::new("/nonexistent"));
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        assert!(download.penalize(addr));
+        // Delivered blocks earn points back, up to the initial score
+        download.reward(addr);
+        download.reward(addr);
+        assert_eq!(download.scores[&addr], 0);
+        assert!(download.penalize(addr));
+        assert!(download.penalize(addr));
+        assert!(!download.penalize(addr));
+    }
+
+    #[test]
+    fn only_peers_timing_out_are_reconnected_to() {
+        let (mut download, _) = download(std::path::Path::new("/nonexistent"));
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let refused = anyhow::anyhow!("connection refused");
+        assert_eq!(download.reconnect_delay(addr, &refused), None);
+        let timed_out = anyhow::Error::from(TimedOut("handshake"));
+        assert_eq!(download.reconnect_delay(addr, &timed_out), Some(RECONNECT_DELAY));
+        assert_eq!(download.reconnect_delay(addr, &timed_out), Some(RECONNECT_DELAY));
+        assert_eq!(download.reconnect_delay(addr, &timed_out), None);
+    }
+
+    #[test]
+    fn request_timeouts_snub_the_peer() {
+        let (mut download, _) = download(std::path::Path::new("/nonexistent"));
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let (slow, other) = (download.register().id, download.register().id);
+        let mut state = PeerState::new(1);
+        state.set_interested(true);
+        state.handle(Message::Unchoke).unwrap();
+        while state.can_request() {
+            let Some(block) = download.next_request(slow, |_| true) else {
+                break;
+            };
+            state.request(block);
+        }
+
+        let expired = state.timed_out(Duration::ZERO);
+        assert_eq!(expired.len(), 2);
+        assert!(state.snubbed);
+        assert!(download.timed_out(slow, addr, &expired));
+        download.report(slow, false, state.am_interested && state.snubbed);
+        assert!(download.transfers[&slow].candidate.snubbed);
+        // The blocks go to other peers, and a single one at a time to the snubbing peer
+        assert_eq!(download.next_request(other, |_| true), Some(expired[0]));
+        let block = download.next_request(slow, |_| true).unwrap();
+        state.request(block);
+        assert!(!state.can_request());
+        let data = vec![0; block.length as usize];
+        state.handle(Message::Piece { index: block.piece, begin: block.begin, block: data }).unwrap();
+        assert!(!state.snubbed && state.can_request());
+    }
+}
@@ -24,7 +24,7 @@ impl<'de> Visitor<'de> for HashesVisitor {
     where
         E: de::Error,
     {
-        if value.len() % 20 != 0 {
+        if !value.len().is_multiple_of(20) {
             return Err(E::custom(format!(
                 "length is {} but a multiple of 20 is expected",
                 value.len()
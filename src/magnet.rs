@@ -0,0 +1,78 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A mutable torrent link, `magnet:?xs=urn:btpk:<public key>&s=<salt>` (BEP 46): the torrent is
+/// the latest one its owner published in the DHT under that key and salt, as a dictionary whose
+/// `ih` key holds the info hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableLink {
+    pub key: [u8; 32],
+    pub salt: Vec<u8>,
+}
+
+impl FromStr for MutableLink {
+    type Err = anyhow::Error;
+
+    fn from_str(link: &str) -> anyhow::Result<Self> {
+        let query = link.strip_prefix("magnet:?").ok_or_else(|| anyhow::anyhow!("not a magnet link"))?;
+        let (mut key, mut salt) = (None, Vec::new());
+        for (name, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+            match name {
+                "xs" => {
+                    let Some(hex_key) = value.strip_prefix("urn:btpk:") else {
+                        continue;
+                    };
+                    let bytes = hex::decode(hex_key).map_err(|e| anyhow::anyhow!("invalid public key: {e}"))?;
+                    key = Some(bytes.try_into().map_err(|_| anyhow::anyhow!("public key is not 32 bytes long"))?);
+                }
+                "s" => salt = hex::decode(value).map_err(|e| anyhow::anyhow!("invalid salt: {e}"))?,
+                _ => {}
+            }
+        }
+        let key = key.ok_or_else(|| anyhow::anyhow!("no urn:btpk public key in the magnet link"))?;
+        Ok(Self { key, salt })
+    }
+}
+
+impl fmt::Display for MutableLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "magnet:?xs=urn:btpk:{}", hex::encode(self.key))?;
+        if !self.salt.is_empty() {
+            write!(f, "&s={}", hex::encode(&self.salt))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548";
+
+    #[test]
+    fn links_hold_a_key_and_an_optional_salt() {
+        let link: MutableLink = format!("magnet:?xs=urn:btpk:{KEY}&s=666f6f626172").parse().unwrap();
+        assert_eq!(hex::encode(link.key), KEY);
+        assert_eq!(link.salt, b"foobar");
+        assert_eq!(link.to_string().parse::<MutableLink>().unwrap(), link);
+
+        // Other parameters are ignored
+        let link: MutableLink = format!("magnet:?dn=name&xs=urn:other:42&xs=urn:btpk:{KEY}").parse().unwrap();
+        assert!(link.salt.is_empty());
+        assert_eq!(link.to_string(), format!("magnet:?xs=urn:btpk:{KEY}"));
+    }
+
+    #[test]
+    fn invalid_links_are_errors() {
+        for link in [
+            format!("xs=urn:btpk:{KEY}"),
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567".to_string(),
+            "magnet:?xs=urn:btpk:77ff".to_string(),
+            format!("magnet:?xs=urn:btpk:{}", KEY.replace('7', "z")),
+            format!("magnet:?xs=urn:btpk:{KEY}&s=zz"),
+        ] {
+            assert!(link.parse::<MutableLink>().is_err(), "{link}");
+        }
+    }
+}
@@ -1,32 +1,78 @@
-use crate::net::{Request, Piece};
 use anyhow::Context;
-use futures_util::{StreamExt, SinkExt};
-use reqwest;
 use clap::{self, Parser, Subcommand};
-use serde_bencode;
-use serde_urlencoded;
 use serde::{self, Deserialize, Serialize};
-use std::{net::{Ipv4Addr, SocketAddrV4}, path::PathBuf, str::FromStr};
-use tokio::{self, io::{AsyncReadExt, AsyncWriteExt}};
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4}, path::PathBuf, str::FromStr, sync::{Arc, Mutex}};
+use std::fmt;
 use tokio::net::TcpStream;
 use sha1::{Digest, Sha1};
 
+mod bitfield;
+mod choker;
+mod config;
 mod decode;
+mod dht;
+mod download;
 mod hash;
+mod magnet;
 mod net;
+mod peer;
+mod pex;
+mod picker;
+mod ratelimit;
+mod resume;
+mod schedule;
+mod session;
+mod storage;
+mod stream;
 mod message;
+#[cfg(test)]
+mod testing;
 
 use hash::Hashes;
-use net::{url_encode, HandShake, TrackerResponse, TrackerSend, PEER_ID};
-use message::{Message, MessageTag, MessageFramer};
-
-const BLOCK_MAX: usize = 1 << 14;
+use picker::{FilePriority, FileSelector};
+use ratelimit::Bandwidth;
+use schedule::{LocalClock, Scheduler};
+use resume::TrackerStats;
+use session::{NewPeer, Session};
+use dht::Dht;
+use dht::items::Item;
+use magnet::MutableLink;
+use storage::{Allocation, Storage};
+use net::{url_encode, Event, TrackerResponse, TrackerSend, PEER_ID};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Command,
+    #[command(flatten)]
+    swarm: SwarmArgs,
+    /// JSON configuration file, holding the bandwidth limits and DHT settings
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Use the scheduled_limits of the configuration at all times, whatever its schedule
+    #[arg(long, global = true)]
+    scheduled_limits: bool,
+}
+
+/// How we reach and are reached by peers
+#[derive(clap::Args, Debug)]
+struct SwarmArgs {
+    /// Port peers connect to us on, as announced to trackers
+    #[arg(long, global = true, default_value_t = 6881)]
+    port: u16,
+    /// Address the peer listener binds to
+    #[arg(long, global = true, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    bind: IpAddr,
+    /// Find peers through the mainline DHT too, on the UDP port of the same number
+    #[arg(long, global = true)]
+    dht: bool,
+    /// Join the IPv6 DHT as well as the IPv4 one, when binding to an IPv4 address
+    #[arg(long, global = true)]
+    dht_ipv6: bool,
+    /// DHT node to join the network through, as HOST:PORT (repeatable)
+    #[arg(long = "dht-node", global = true)]
+    dht_nodes: Vec<String>,
 }
 
 #[allow(unused)]
@@ -48,27 +94,105 @@ enum Command {
         torrent: PathBuf, 
         piece: usize,
     },
+    #[command(about = "Download a whole torrent from all the peers given by its tracker into a directory")]
+    Download {
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+        /// How the files get their space on disk
+        #[arg(long, value_enum, default_value_t)]
+        allocation: Allocation,
+        /// Only download the files matching this index or glob on their path (repeatable)
+        #[arg(long)]
+        only: Vec<FileSelector>,
+        /// Priority of the files matching an index or glob, as SELECTOR=skip|low|normal|high (repeatable)
+        #[arg(long = "priority")]
+        priorities: Vec<FilePriority>,
+        /// Download every file in order, for playback while downloading
+        #[arg(long)]
+        sequential: bool,
+        /// Download the files matching this index or glob in order (repeatable)
+        #[arg(long = "sequential-file")]
+        sequential_files: Vec<FileSelector>,
+        /// Number of pieces of sequential files fetched ahead of anything else
+        #[arg(long, default_value_t = 8)]
+        readahead: usize,
+        /// Serve the files over HTTP on this port while they download, with seeking support
+        #[arg(long)]
+        stream: Option<u16>,
+        /// Address the HTTP server listens on
+        #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+        stream_bind: IpAddr,
+    },
+    #[command(about = "Store a string in the DHT, printing the hex key it can be got back with")]
+    DhtPut { value: String },
+    #[command(about = "Get a value stored in the DHT under a hex key")]
+    DhtGet { target: String },
+    #[command(about = "Estimate the numbers of seeds and peers of a torrent from the DHT, given its hex info hash")]
+    DhtScrape { info_hash: String },
+    #[command(about = "List info hashes of torrents found in the DHT by sampling its nodes")]
+    DhtSample {
+        /// Stop after finding this many
+        #[arg(long, default_value_t = 100)]
+        count: usize,
+    },
+    #[command(about = "Publish a torrent in the DHT as the latest of a mutable torrent link, until interrupted")]
+    Publish {
+        torrent: PathBuf,
+        /// File holding the ed25519 secret key the link is derived from, created if missing
+        #[arg(long)]
+        key: PathBuf,
+        /// Tells apart the links of a key, e.g. one per release channel
+        #[arg(long, default_value = "")]
+        salt: String,
+    },
+    #[command(about = "Find the torrent a mutable torrent link currently points to")]
+    Resolve { link: MutableLink },
+    #[command(about = "Upload a completely downloaded torrent from its data directory until interrupted")]
+    Seed {
+        torrent: PathBuf,
+        /// Directory holding the data of the torrent, as given to `download -o`
+        data: PathBuf,
+        /// Reveal pieces one at a time so that peers spread them, for the initial seed of a torrent.
+        /// With a single peer, no piece is revealed past the first one.
+        #[arg(long = "super-seed")]
+        super_seed: bool,
+    },
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize)]
 struct Torrent {
-    // The tracker URL, which the client will connect to to find peers
+    // The tracker URL, which the client will connect to to find peers. Missing from trackerless torrents
+    #[serde(default)]
     announce: String,
+    // DHT nodes to bootstrap from, given by trackerless torrents
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    nodes: Vec<TorrentNode>,
     // Miscellaneous info about the torrent file
     info: Info,
 }
 
 impl Torrent {
+    /// Total length in bytes of the torrent's content
+    pub fn length(&self) -> usize {
+        match &self.info.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    /// Whether peers may only be found through the tracker, and not exchanged with other peers
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
     /// Get the SHA-1 info hash of the torrent (20 bytes)
     pub fn info_hash(&self) -> [u8; 20] {
         // Bencode into bytes the torrent's info field before hashing
         let info_encoded = serde_bencode::to_bytes(&self.info).expect("re-encode info section");
         let mut hasher = Sha1::new();
         hasher.update(&info_encoded);
-        hasher
-            .finalize()
-            .try_into()
-            .expect("Supposed to be a GenericArray cast-able to [u8; 20]")
+        hasher.finalize().into()
     }
 }
 
@@ -88,6 +212,10 @@ struct Info {
     /// Each entry of `pieces` is the SHA1 hash of the piece at the corresponding index.
     pieces: Hashes,
 
+    /// Set to 1 by private torrents (BEP 27), whose peers come from their tracker only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
+
     #[serde(flatten)]
     keys: Keys,
 }
@@ -107,18 +235,343 @@ impl Info {
 #[serde(untagged)]
 enum Keys {
     SingleFile { length: usize }, // Most common
-    MultiFile { file: File },
+    MultiFile { files: Vec<File> },
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize)]
 struct File {
     length: usize,
-    path: Vec<String>, // !!! Not implemented !!!
+    path: Vec<String>, // Subdirectory names, the last of which being the actual file name
+}
+
+/// A DHT node of a torrent file, encoded as a `[host, port]` list
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct TorrentNode {
+    host: String,
+    port: u16,
+}
+
+impl<'de> Deserialize<'de> for TorrentNode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = TorrentNode;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a [host, port] list")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                use serde::de::Error;
+                let host: serde_bytes::ByteBuf = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(0, &self))?;
+                let port = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(1, &self))?;
+                // The list must be read to its end for the decoder to carry on after it
+                while seq.next_element::<serde::de::IgnoredAny>()?.is_some() {}
+                let host = String::from_utf8(host.into_vec()).map_err(A::Error::custom)?;
+                Ok(TorrentNode { host, port })
+            }
+        }
+
+        deserializer.deserialize_seq(Visitor)
+    }
+}
+
+impl Serialize for TorrentNode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (&self.host, self.port).serialize(serializer)
+    }
+}
+
+/// Announce ourselves to the torrent's tracker, which answers with a list of peers
+async fn announce(
+    torrent: &Torrent,
+    port: u16,
+    stats: TrackerStats,
+    left: usize,
+    event: Option<Event>,
+) -> anyhow::Result<TrackerResponse> {
+    anyhow::ensure!(!torrent.announce.is_empty(), "torrent has no tracker");
+    // Tracker GET request
+    let tracker_send = TrackerSend {
+        peer_id: String::from(PEER_ID),
+        port,
+        downloaded: stats.downloaded,
+        uploaded: stats.uploaded,
+        left,
+        compact: 1,
+        event,
+    };
+
+    // Bake the URL from the tracker_send structure instance (URL like: "peer_id=XXXX&port=XXXX&downloaded=0")
+    let request_params_url = serde_urlencoded::to_string(&tracker_send).context("Url-encode the tracker params")?;
+    // Form the URL from tracker URL, params and the URL_encoded info hash of the torrent
+    let tracker_url = format!("{}?{}&info_hash={}", torrent.announce, request_params_url, &url_encode(&torrent.info_hash()));
+
+    // Send the request to the tracker and build a response
+    let tracker_response = reqwest::get(tracker_url).await.context("Request failed at sending...")?;
+    let tracker_response = tracker_response.bytes().await.context("Tracker response")?;
+    serde_bencode::from_bytes(&tracker_response).context("Parse to tracker response")
+}
+
+/// Start accepting peer connections, for the torrents then added to the returned session. Should
+/// the port be taken, peers are only connected to.
+async fn listen(bind: IpAddr, port: u16) -> Arc<Session> {
+    let session = Arc::new(Session::default());
+    match tokio::net::TcpListener::bind(SocketAddr::new(bind, port)).await {
+        Ok(listener) => {
+            tokio::spawn(Arc::clone(&session).listen(listener));
+        }
+        Err(e) => eprintln!("Not accepting peer connections, listening on {bind}:{port} failed: {e}"),
+    }
+    session
+}
+
+/// Peers of a torrent and the means of finding more
+struct Swarm {
+    /// Peers given by the tracker
+    peers: Vec<SocketAddrV4>,
+    /// Peers found later or connecting to us
+    incoming: Option<tokio::sync::mpsc::Receiver<NewPeer>>,
+    dht: Option<Arc<Dht>>,
+    /// Task announcing to the tracker, and the means of telling it we leave
+    tracker: Option<(tokio::sync::oneshot::Sender<()>, tokio::task::JoinHandle<()>)>,
+}
+
+impl Swarm {
+    async fn leave(&mut self, config: &config::Config) {
+        if let Some((stop, task)) = self.tracker.take() {
+            let _ = stop.send(());
+            let _ = task.await;
+        }
+        if let Some(dht) = &self.dht {
+            save_dht(dht, config).await;
+        }
+    }
+}
+
+/// Find the peers of a torrent through its tracker, announced to again as it asks, and when
+/// enabled the DHT
+async fn join_swarm(
+    arg: &SwarmArgs,
+    config: &config::Config,
+    torrent: &Torrent,
+    download: &Arc<Mutex<download::Download>>,
+) -> anyhow::Result<Swarm> {
+    let (stats, left) = {
+        let download = download.lock().expect("download lock poisoned");
+        (download.stats(), download.left())
+    };
+    let session = listen(arg.bind, arg.port).await;
+    let incoming = session.add(torrent.info_hash());
+    let mut dht = None;
+    if arg.dht {
+        let (node, bootstrap) = start_dht(arg, config, &torrent.nodes).await?;
+        tokio::spawn(discover(Arc::clone(&node), Arc::clone(&session), torrent.info_hash(), arg.port, left == 0, bootstrap));
+        dht = Some(node);
+    }
+    let (peers, interval) = match announce(torrent, arg.port, stats, left, Some(Event::Started)).await {
+        Ok(response) => (response.peers.0, response.interval),
+        // Trackerless operation, the tracker being tried again later if any
+        Err(e) if arg.dht => {
+            eprintln!("Tracker unavailable, relying on the DHT: {e:#}");
+            (Vec::new(), 0)
+        }
+        Err(e) => return Err(e),
+    };
+    let tracker = (!torrent.announce.is_empty()).then(|| {
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        let download = Arc::clone(download);
+        let task = tokio::spawn(reannounce(torrent.clone(), arg.port, download, session, interval, stopped));
+        (stop, task)
+    });
+    Ok(Swarm {
+        peers,
+        incoming: Some(incoming),
+        dht,
+        tracker,
+    })
+}
+
+/// Start a DHT node with the state saved last time, returning it with the nodes to bootstrap from
+async fn start_dht(
+    arg: &SwarmArgs,
+    config: &config::Config,
+    torrent_nodes: &[TorrentNode],
+) -> anyhow::Result<(Arc<Dht>, Vec<SocketAddr>)> {
+    // Both families on the unspecified addresses, otherwise the family of the bind address
+    let mut addrs = vec![SocketAddr::new(arg.bind, arg.port)];
+    match arg.bind {
+        IpAddr::V4(_) if arg.dht_ipv6 => addrs.push(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), arg.port)),
+        IpAddr::V6(ip) if ip.is_unspecified() => addrs.push(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), arg.port)),
+        _ => {}
+    }
+    let saved = match &config.dht_state {
+        Some(path) => dht::Saved::load(path).await.unwrap_or_else(|e| {
+            eprintln!("Ignoring DHT state {}: {e:#}", path.display());
+            None
+        }),
+        None => None,
+    };
+    let dht = Dht::bind(&addrs, saved.as_ref(), config.dht_enforce_node_ids).await?;
+
+    // Nodes known from last time first, then the well-known ones
+    let mut bootstrap = saved.map(|saved| saved.nodes()).unwrap_or_default();
+    for host in config.dht_bootstrap.iter().chain(&arg.dht_nodes) {
+        bootstrap.extend(resolve(host.as_str()).await);
+    }
+    for node in torrent_nodes {
+        bootstrap.extend(resolve((node.host.as_str(), node.port)).await);
+    }
+    Ok((dht, bootstrap))
+}
+
+/// Save the DHT routing table for the next run
+async fn save_dht(dht: &Dht, config: &config::Config) {
+    if let Some(path) = &config.dht_state {
+        if let Err(e) = dht.save(path).await {
+            eprintln!("Saving DHT state to {}: {e:#}", path.display());
+        }
+    }
+}
+
+/// A 20 byte hash given in hex
+fn parse_hash(hash: &str) -> anyhow::Result<[u8; 20]> {
+    hex::decode(hash)
+        .ok()
+        .and_then(|hash| <[u8; 20]>::try_from(hash).ok())
+        .with_context(|| format!("{hash} is not 20 bytes in hex"))
+}
+
+/// Load the ed25519 key of a publisher of mutable torrents, creating it if missing
+fn load_key(path: &std::path::Path) -> anyhow::Result<ed25519_dalek::SigningKey> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::io::Write;
+
+    let seed: [u8; 32] = match std::fs::read(path) {
+        Ok(bytes) => bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("{} is not a 32 byte ed25519 secret key", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let seed: [u8; 32] = rand::random();
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .with_context(|| format!("create key file {}", path.display()))?;
+            file.write_all(&seed).context("write key file")?;
+            eprintln!("Created key {}", path.display());
+            seed
+        }
+        Err(e) => return Err(e).with_context(|| format!("read key file {}", path.display())),
+    };
+    Ok(ed25519_dalek::SigningKey::from_bytes(&seed))
+}
+
+/// Addresses of a DHT node given by name, none if it cannot be resolved
+async fn resolve(host: impl tokio::net::ToSocketAddrs + fmt::Debug + Copy) -> Vec<SocketAddr> {
+    match tokio::net::lookup_host(host).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            eprintln!("Resolving DHT node {host:?}: {e}");
+            Vec::new()
+        }
+    }
+}
+
+/// Announce ourselves to the tracker again every `interval` seconds, as it asked, with the current
+/// transfer stats, handing the peers it gives to the torrent as long as it is in the session. The
+/// completion of the download is announced right away, and leaving once `stop` fires.
+async fn reannounce(
+    torrent: Torrent,
+    port: u16,
+    download: Arc<Mutex<download::Download>>,
+    session: Arc<Session>,
+    mut interval: usize,
+    mut stop: tokio::sync::oneshot::Receiver<()>,
+) {
+    /// Shortest interval, should the tracker ask for less or be unavailable
+    const MIN_INTERVAL: usize = 60;
+    /// Time given to the tracker to acknowledge that we leave
+    const STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    // Downloads complete at start, like seeds, do not complete again
+    let mut complete = download.lock().expect("download lock poisoned").is_complete();
+    loop {
+        let event = tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(interval.max(MIN_INTERVAL) as u64)) => None,
+            _ = download::wait_for_completion(&download), if !complete => Some(Event::Completed),
+            _ = &mut stop => Some(Event::Stopped),
+        };
+        complete |= event == Some(Event::Completed);
+        let (stats, left) = {
+            let download = download.lock().expect("download lock poisoned");
+            (download.stats(), download.left())
+        };
+        if event == Some(Event::Stopped) {
+            match tokio::time::timeout(STOP_TIMEOUT, announce(&torrent, port, stats, left, event)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("Announcing to the tracker that we leave: {e:#}"),
+                Err(_) => eprintln!("Announcing to the tracker that we leave: timed out"),
+            }
+            break;
+        }
+        let peers = match announce(&torrent, port, stats, left, event).await {
+            Ok(response) => {
+                interval = response.interval;
+                response.peers.0
+            }
+            // Tried again after the same interval
+            Err(e) => {
+                eprintln!("Announcing to the tracker: {e:#}");
+                Vec::new()
+            }
+        };
+        if !session.discovered(&torrent.info_hash(), peers.into_iter().map(SocketAddr::V4)).await {
+            break;
+        }
+    }
+}
+
+/// Periodically look up peers of a torrent in the DHT and announce ourselves there, as a seed if we
+/// have it all, as long as the torrent is in the session
+async fn discover(dht: Arc<Dht>, session: Arc<Session>, info_hash: [u8; 20], port: u16, seed: bool, bootstrap: Vec<SocketAddr>) {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+    dht.bootstrap(&bootstrap).await;
+    loop {
+        let peers = dht.announce(info_hash, port, seed).await;
+        eprintln!("DHT found {} peers, {} nodes known", peers.len(), dht.nodes());
+        if !session.discovered(&info_hash, peers).await {
+            break;
+        }
+        tokio::time::sleep(INTERVAL).await;
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let arg = Args::parse();
+    let config = match &arg.config {
+        Some(path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+    let bandwidth = if arg.scheduled_limits {
+        Bandwidth::new(config.scheduled_limits)
+    } else {
+        let scheduler = Scheduler {
+            regular: config.limits,
+            scheduled: config.scheduled_limits,
+            windows: config.schedule.clone(),
+            clock: LocalClock,
+        };
+        let bandwidth = Bandwidth::new(scheduler.limits());
+        if !scheduler.windows.is_empty() {
+            tokio::spawn(scheduler.run(Arc::clone(&bandwidth)));
+        }
+        bandwidth
+    };
 
     match arg.command {
         Command::Decode { encoded } => { // Decoded a raw bencoded string
@@ -132,10 +585,11 @@ async fn main() -> anyhow::Result<()> {
 
             println!("Tracker: {}", torrent.announce);
 
-            if let Keys::SingleFile { length } = torrent.info.keys {
-                println!("Length: {}", length);
-            } else {
-                unimplemented!();
+            println!("Length: {}", torrent.length());
+            if let Keys::MultiFile { files } = &torrent.info.keys {
+                for (index, file) in files.iter().enumerate() {
+                    println!("  {index}: {} ({} bytes)", file.path.join("/"), file.length);
+                }
             }
             println!("{}", torrent.info.piece_length);
 
@@ -149,31 +603,7 @@ async fn main() -> anyhow::Result<()> {
         Command::Peers { torrent } => { // Find peers with the tracker announce
             let content = std::fs::read(torrent).expect("Content reading error");
             let torrent: Torrent = serde_bencode::from_bytes(&content).expect("Deserializing error");
-            let length = if let Keys::SingleFile { length } = torrent.info.keys {
-                length
-            } else {
-                todo!();
-            };
-
-            // Tracker GET request
-            let tracker_send = TrackerSend {
-                peer_id: String::from(PEER_ID),
-                port: 6881, // Magical constant
-                downloaded: 0, // Nothing downloaded at first
-                uploaded: 0, // Nothing uploaded at first
-                left: length, // Nothing downloaded at first
-                compact: 1,
-            };
-            
-            // Bake the URL from the tracker_send structure instance (URL like: "peer_id=XXXX&port=XXXX&downloaded=0")
-            let request_params_url = serde_urlencoded::to_string(&tracker_send).context("Url-encode the tracker params")?;
-            // Form the URL from tracker URL, params and the URL_encoded info hash of the torrent
-            let tracker_url = format!("{}?{}&info_hash={}", torrent.announce, request_params_url, &url_encode(&torrent.info_hash()));
-
-            // Send the request to the tracker and build a response
-            let tracker_response = reqwest::get(tracker_url).await.expect("Request failed at sending...");
-            let tracker_response = tracker_response.bytes().await.context("Tracker response")?;
-            let tracker_response: TrackerResponse = serde_bencode::from_bytes(&tracker_response).context("Parse to tracker response")?;
+            let tracker_response = announce(&torrent, arg.swarm.port, TrackerStats::default(), torrent.length(), None).await?;
     
             println!("{}", tracker_response.interval);
             for peer in tracker_response.peers.0 {
@@ -195,15 +625,7 @@ async fn main() -> anyhow::Result<()> {
             // Connect to the peer
             let mut stream = TcpStream::connect(peer).await.context("TCP connection to peer")?;
 
-            // Create a handshake, 
-            let mut handshake = HandShake::new(info_hash, *b"00112233445566778899"/*Default*/);
-            let handshake_bytes = handshake.as_bytes_mut();
-
-            stream.write_all(handshake_bytes).await.context("writing handshake via TCP to peer")?;
-            stream.read_exact(handshake_bytes).await.context("reading handshake from peer")?;
-            // Some magical checks
-            assert!(handshake.len == 19);
-            assert!(&handshake.bittorrent == b"BitTorrent protocol");
+            let handshake = net::handshake(&mut stream, info_hash).await?;
 
             println!("Peer_id of handshake (hex): {}", hex::encode(handshake.peer_id));
         }
@@ -211,125 +633,219 @@ async fn main() -> anyhow::Result<()> {
         Command::DownloadPiece { output, torrent, piece: piece_i} => {
             let content = std::fs::read(torrent).expect("Content reading error");
             let torrent: Torrent = serde_bencode::from_bytes(&content).expect("Deserializing error");
-            let length = if let Keys::SingleFile { length } = torrent.info.keys {
-                length
-            } else {
-                todo!();
-            };
+            anyhow::ensure!(piece_i < torrent.info.pieces.0.len(), "Torrent has no piece {piece_i}");
             println!("Piece length {}", torrent.info.piece_length);
-            assert!(piece_i < torrent.info.pieces.0.len());
-            let info_hash = torrent.info_hash();
-            let tracker_send = TrackerSend {
-                peer_id: String::from(PEER_ID),
-                port: 6881,
-                downloaded: 0,
-                uploaded: 0,
-                left: length,
-                compact: 1,
+
+            // The piece lands in the files of the torrent, laid out in a scratch directory
+            let scratch = tempfile::tempdir().context("create scratch directory")?;
+            let storage = Arc::new(Storage::new(&torrent.info, scratch.path())?);
+            let download = Arc::new(Mutex::new(download::Download::new(&torrent, Arc::clone(&storage), [piece_i])));
+            let mut swarm = join_swarm(&arg.swarm, &config, &torrent, &download).await?;
+            let downloaded = download::run(download, &torrent, &swarm.peers, swarm.incoming.take(), arg.swarm.port, bandwidth.torrent(), None).await;
+            swarm.leave(&config).await;
+            downloaded?;
+
+            let blocks = storage.read_piece(piece_i).await?;
+            tokio::fs::write(&output, blocks).await.context("write out downloaded piece")?;
+            println!("Piece {piece_i} downloaded to {}.", output.display());
+        }
+
+        Command::Download { output, torrent, allocation, only, priorities, sequential, sequential_files, readahead, stream, stream_bind } => {
+            let content = std::fs::read(torrent).expect("Content reading error");
+            let torrent: Torrent = serde_bencode::from_bytes(&content).expect("Deserializing error");
+
+            let files = picker::file_priorities(&torrent, &only, &priorities)?;
+            let mut storage = Storage::new(&torrent.info, &output)?;
+            storage.skip_files(&files);
+            let sequential = picker::file_sequential(&torrent, sequential, &sequential_files)?;
+            let pieces = picker::piece_plan(&storage, torrent.info.pieces.0.len(), &files, &sequential);
+            let storage = Arc::new(storage);
+            let resume_path = resume::path(&output, &torrent.info_hash());
+            let progress = resume::restore(&torrent, &storage, &resume_path).await?;
+            storage.allocate(allocation).await?;
+
+            let mut download = download::Download::new(&torrent, storage, pieces);
+            download.set_readahead(readahead);
+            download.restore(&progress);
+            let complete = download.is_complete();
+            let download = Arc::new(Mutex::new(download));
+
+            let server = match stream {
+                Some(port) => {
+                    let listener = tokio::net::TcpListener::bind(SocketAddr::new(stream_bind, port))
+                        .await
+                        .context("bind HTTP server")?;
+                    let paths = picker::file_paths(&torrent);
+                    for url in stream::urls(listener.local_addr()?, &torrent.info_hash(), &paths) {
+                        println!("Streaming {url}");
+                    }
+                    Some(tokio::spawn(stream::serve(listener, torrent.info_hash(), paths, Arc::clone(&download))))
+                }
+                None => None,
             };
+            if !complete {
+                let mut swarm = join_swarm(&arg.swarm, &config, &torrent, &download).await?;
+                let downloaded = download::run(Arc::clone(&download), &torrent, &swarm.peers, swarm.incoming.take(), arg.swarm.port, bandwidth.torrent(), Some(resume_path)).await;
+                swarm.leave(&config).await;
+                downloaded?;
+            }
 
-            
-            let request_params_url = serde_urlencoded::to_string(&tracker_send).context("Url-encode the tracker params")?;
-            let tracker_url = format!("{}?{}&info_hash={}", torrent.announce, request_params_url, &url_encode(&torrent.info_hash()));
+            println!("Downloaded {} to {}.", torrent.info.name, output.display());
+            if let Some(server) = server {
+                println!("Still streaming, press Ctrl-C to stop");
+                tokio::select! {
+                    served = server => served.context("join HTTP server")??,
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+        }
 
-            let tracker_response = reqwest::get(tracker_url).await.expect("Request failed at sending...");
-            let tracker_response = tracker_response.bytes().await.context("Tracker response")?;
-            let tracker_response: TrackerResponse = serde_bencode::from_bytes(&tracker_response).context("Parse to tracker response")?;
+        Command::DhtPut { value } => {
+            let (dht, bootstrap) = start_dht(&arg.swarm, &config, &[]).await?;
+            dht.bootstrap(&bootstrap).await;
+            let item = Item { value: serde_bencode::value::Value::Bytes(value.into_bytes()), signed: None };
+            let stored = dht.put(&item).await;
+            save_dht(&dht, &config).await;
+            println!("Stored on {} nodes under {}", stored?, hex::encode(item.target()?));
+        }
 
-            let peer = &tracker_response.peers.0[0]; // Pick up a random peer
-            let mut peer = TcpStream::connect(peer).await.context("TCP connection to peer")?;
+        Command::DhtGet { target } => {
+            let target = parse_hash(&target)?;
+            let (dht, bootstrap) = start_dht(&arg.swarm, &config, &[]).await?;
+            dht.bootstrap(&bootstrap).await;
+            let value = dht.get(target).await;
+            save_dht(&dht, &config).await;
+            match value.context("nothing stored under this key")? {
+                serde_bencode::value::Value::Bytes(bytes) => println!("{}", String::from_utf8_lossy(&bytes)),
+                value => println!("{value:?}"),
+            }
+        }
 
-            let mut handshake = HandShake::new(info_hash, *b"00112233445566778899");
-            let handshake_bytes = handshake.as_bytes_mut();
+        Command::DhtScrape { info_hash } => {
+            let info_hash = parse_hash(&info_hash)?;
+            let (dht, bootstrap) = start_dht(&arg.swarm, &config, &[]).await?;
+            dht.bootstrap(&bootstrap).await;
+            let scrape = dht.scrape(info_hash).await;
+            save_dht(&dht, &config).await;
+            println!("Seeds: ~{:.0}", scrape.seeds);
+            println!("Peers: ~{:.0}", scrape.peers);
+        }
 
-            peer.write_all(handshake_bytes).await.context("writing handshake via TCP to peer")?;
-            peer.read_exact(handshake_bytes).await.context("reading handshake")?;
-            
-            assert!(handshake.len == 19);
-            assert!(&handshake.bittorrent == b"BitTorrent protocol");
-            println!("Peer_id of handshake (hex): {}", hex::encode(handshake.peer_id));
+        Command::DhtSample { count } => {
+            let (dht, bootstrap) = start_dht(&arg.swarm, &config, &[]).await?;
+            dht.bootstrap(&bootstrap).await;
+            let info_hashes = dht.crawl(count).await;
+            save_dht(&dht, &config).await;
+            for info_hash in info_hashes {
+                println!("{}", hex::encode(info_hash));
+            }
+        }
 
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer); // Peer is framed
-
-            // In-order steps for file retrieving:
-
-            // #1: Wait for bitfield from peer(s)
-            let bitfield = peer.next().await.expect("Peer always first sends a bitfield").expect("Bitfield was invalid");
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-            //Ignore payload
-            // #2: Send Interested
-            peer.send(Message {
-                length: 1,
-                tag: MessageTag::Interested,
-                payload: Vec::new() // Empty
-            }).await.context("Send Interested")?;
-
-            // #3: Wait for unchoke from peer(s)
-            let unchoke = peer.next().await.expect("Peer always sends a unchoke").expect("Unchoke was invalid");
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty()); // Should be the case if previous assertions were passed according to the protocol
-
-            // #4: Send Request for all blocks of a file piece
-            let piece_hash = &torrent.info.pieces.0[piece_i];
-            let piece_size = if piece_i == torrent.info.pieces.0.len() + 1 {
-                let md = length % torrent.info.piece_length;
-                if md == 0 {
-                    torrent.info.piece_length
-                } else {
-                    md
+        Command::Publish { torrent, key, salt } => {
+            let content = std::fs::read(torrent).expect("Content reading error");
+            let torrent: Torrent = serde_bencode::from_bytes(&content).expect("Deserializing error");
+            let key = load_key(&key)?;
+            let link = MutableLink { key: key.verifying_key().to_bytes(), salt: salt.into_bytes() };
+            let value = serde_bencode::value::Value::Dict(std::collections::HashMap::from([(
+                b"ih".to_vec(),
+                serde_bencode::value::Value::Bytes(torrent.info_hash().to_vec()),
+            )]));
+
+            let (dht, bootstrap) = start_dht(&arg.swarm, &config, &torrent.nodes).await?;
+            dht.bootstrap(&bootstrap).await;
+            // Republishing the current torrent keeps its version, a new one gets the next; the
+            // clock keeps versions increasing should the current one not be found
+            let seq = match dht.get_mutable(&link.key, &link.salt).await {
+                Some(current) if current.value == value => current.signed.map_or(0, |signed| signed.seq),
+                current => {
+                    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs() as i64;
+                    now.max(current.and_then(|current| current.signed).map_or(0, |signed| signed.seq + 1))
                 }
-            } else {
-                torrent.info.piece_length
             };
-            println!("Calculated piece size: {}", piece_size);
-
-            let nblocks = usize::div_ceil(piece_size, BLOCK_MAX); // Ceil for the potentially truncated block
-            eprintln!("{}", nblocks);
-            let mut blocks: Vec<u8> = Vec::with_capacity(piece_size);
-            for block_i in 0..nblocks {
-                let block_size = if block_i == nblocks - 1 {
-                    let md = piece_size % BLOCK_MAX;
-                    if md == 0 {
-                        BLOCK_MAX
-                    } else {
-                        md
-                    }
-                } else {
-                    BLOCK_MAX
-                };
-                eprintln!("block #{block_i} is {block_size}b");
-                let mut request = Request::new(
-                    piece_i as u32,
-                    (block_i * BLOCK_MAX) as u32,
-                    block_size as u32,
-                );
-                let request_bytes = request.as_bytes_mut();
-                peer.send(Message { length: (request_bytes.len()+1) as u32, tag: MessageTag::Request, payload: request_bytes.to_vec() }).await.context("Send block request")?;
-                // # Wait for a piece message
-                let piece = peer.next().await.expect("Peer always sends a piece").expect("Piece was invalid");
-                assert_eq!(piece.tag, MessageTag::Piece);
-                assert!(!piece.payload.is_empty());
-
-                let piece = (&piece.payload[..]) as *const [u8] as *const Piece;
-                let piece = unsafe {
-                    &*piece
-                };
-                assert_eq!(piece.index() as usize, piece_i);
-                blocks.extend(piece.block());
+            let item = Item::signed(&key, &link.salt, seq, value)?;
+            println!("Publishing {} as {link}, press Ctrl-C to stop", torrent.info.name);
+
+            // Items are forgotten after two hours unless put again
+            let mut refresh = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+            loop {
+                tokio::select! {
+                    _ = refresh.tick() => println!("Version {seq} stored on {} nodes", dht.put(&item).await?),
+                    _ = tokio::signal::ctrl_c() => break,
+                }
             }
+            save_dht(&dht, &config).await;
+        }
 
-            assert_eq!(blocks.len(), piece_size);
-            
-            let mut hasher = Sha1::new();
-            hasher.update(&blocks);
-            let hash: [u8; 20] = hasher
-                .finalize()
-                .try_into()
-                .expect("Supposed to be a GenericArray cast-able to [u8; 20]");
-            assert_eq!(&hash, piece_hash);
-            tokio::fs::write(&output, blocks).await.context("write out downloaded piece")?;
-            println!("Piece {piece_i} downloaded to {}.", output.display());
+        Command::Resolve { link } => {
+            let (dht, bootstrap) = start_dht(&arg.swarm, &config, &[]).await?;
+            dht.bootstrap(&bootstrap).await;
+            let item = dht.get_mutable(&link.key, &link.salt).await;
+            save_dht(&dht, &config).await;
+            let item = item.context("nothing published under this link")?;
+            let info_hash = match &item.value {
+                serde_bencode::value::Value::Dict(dict) => match dict.get(&b"ih"[..]) {
+                    Some(serde_bencode::value::Value::Bytes(info_hash)) if info_hash.len() == 20 => hex::encode(info_hash),
+                    _ => anyhow::bail!("the published item holds no info hash"),
+                },
+                _ => anyhow::bail!("the published item is not a dictionary"),
+            };
+            println!("Version: {}", item.signed.map_or(0, |signed| signed.seq));
+            println!("Info hash: {info_hash}");
+            println!("magnet:?xt=urn:btih:{info_hash}");
+        }
+
+        Command::Seed { torrent, data, super_seed } => {
+            let content = std::fs::read(torrent).expect("Content reading error");
+            let torrent: Torrent = serde_bencode::from_bytes(&content).expect("Deserializing error");
+
+            let storage = Arc::new(Storage::new(&torrent.info, &data)?);
+            let resume_path = resume::path(&data, &torrent.info_hash());
+            let progress = resume::restore(&torrent, &storage, &resume_path).await?;
+            anyhow::ensure!(
+                progress.have.is_complete(),
+                "only {}/{} pieces of {} are in {}",
+                progress.have.count(),
+                progress.have.piece_count(),
+                torrent.info.name,
+                data.display()
+            );
+
+            let mut download = download::Download::new(&torrent, storage, 0..0);
+            download.restore(&progress);
+            download.set_seeding(true);
+            download.set_super_seeding(super_seed);
+            let download = Arc::new(Mutex::new(download));
+            let mut swarm = join_swarm(&arg.swarm, &config, &torrent, &download).await?;
+            println!("Seeding {}, press Ctrl-C to stop", torrent.info.name);
+            let seeded = download::run(Arc::clone(&download), &torrent, &swarm.peers, swarm.incoming.take(), arg.swarm.port, bandwidth.torrent(), Some(resume_path)).await;
+            swarm.leave(&config).await;
+            seeded?;
+            println!("Uploaded {} bytes.", download.lock().expect("download lock poisoned").stats().uploaded);
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torrent_nodes_are_host_port_lists() {
+        let (torrent, _) = testing::torrent(&[10], 16);
+        let mut encoded = serde_bencode::to_bytes(&torrent).unwrap();
+        assert_eq!(encoded.pop(), Some(b'e'));
+        encoded.extend_from_slice(b"5:nodesll11:example.orgi6881eel7:1.2.3.4i6882e5:extraee");
+        encoded.push(b'e');
+
+        let torrent: Torrent = serde_bencode::from_bytes(&encoded).unwrap();
+        let node = |host: &str, port| TorrentNode { host: host.to_string(), port };
+        assert_eq!(torrent.nodes, [node("example.org", 6881), node("1.2.3.4", 6882)]);
+        assert_eq!(torrent.length(), 10);
+        let reencoded: Torrent = serde_bencode::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+        assert_eq!(reencoded.nodes, torrent.nodes);
+
+        let invalid = b"d8:announce0:5:nodesll11:example.orgee4:infod4:name1:t12:piece lengthi16e6:pieces0:6:lengthi0eee";
+        assert!(serde_bencode::from_bytes::<Torrent>(invalid).is_err());
+    }
+}
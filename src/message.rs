@@ -1,9 +1,10 @@
-use anyhow::Context;
+use crate::bitfield::Bitfield;
+use crate::net::ParseError;
 use bytes::{Buf, BufMut, BytesMut};
 use strum_macros::FromRepr;
 use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(FromRepr, Debug, Clone, PartialEq, Eq)]
+#[derive(FromRepr, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MessageTag {
     Choke = 0,
@@ -15,82 +16,236 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
+    Extended = 20,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub struct Message {
-    pub length: u32, // Accounts for tag length (1) + payload length (variable)
-    pub tag: MessageTag,
-    pub payload: Vec<u8>,
+/// A peer-wire message, framed on the wire as a big-endian length prefix, a tag and a payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive, // Length prefix of 0, no tag
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bitfield),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port(u16), // DHT port of the peer
+    Extended { id: u8, payload: Vec<u8> }, // BEP 10 extension message
 }
 
-pub struct MessageFramer;
-
-const MAX: usize = 1 << 16;
-
-impl Decoder for MessageFramer {
-    type Item = Message;
-    type Error = std::io::Error;
+impl Message {
+    pub fn tag(&self) -> Option<MessageTag> {
+        Some(match self {
+            Message::KeepAlive => return None,
+            Message::Choke => MessageTag::Choke,
+            Message::Unchoke => MessageTag::Unchoke,
+            Message::Interested => MessageTag::Interested,
+            Message::NotInterested => MessageTag::NotInterested,
+            Message::Have(_) => MessageTag::Have,
+            Message::Bitfield(_) => MessageTag::Bitfield,
+            Message::Request { .. } => MessageTag::Request,
+            Message::Piece { .. } => MessageTag::Piece,
+            Message::Cancel { .. } => MessageTag::Cancel,
+            Message::Port(_) => MessageTag::Port,
+            Message::Extended { .. } => MessageTag::Extended,
+        })
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 4 {
-            return Ok(None);
+    /// Length of the whole frame on the wire, length prefix included
+    pub fn wire_len(&self) -> usize {
+        match self {
+            Message::KeepAlive => 4,
+            _ => 4 + 1 + self.payload_len(),
         }
+    }
 
-        // Read length marker.
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let len = u32::from_be_bytes(length_bytes);
-        let len_u = len as usize;
+    /// Length of the payload, tag excluded
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested => 0,
+            Message::Have(_) => 4,
+            Message::Bitfield(bitfield) => bitfield.as_bytes().len(),
+            Message::Request { .. } | Message::Cancel { .. } => 12,
+            Message::Piece { block, .. } => 8 + block.len(),
+            Message::Port(_) => 2,
+            Message::Extended { payload, .. } => 1 + payload.len(),
+        }
+    }
 
-        if len == 0 {
-            //this is a heartbeat message
-            src.advance(4);
-            return self.decode(src);
+    fn put_payload(&self, dst: &mut BytesMut) {
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested => {}
+            Message::Have(index) => dst.put_u32(*index),
+            Message::Bitfield(bitfield) => dst.extend_from_slice(bitfield.as_bytes()),
+            Message::Request { index, begin, length } | Message::Cancel { index, begin, length } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
+            }
+            Message::Piece { index, begin, block } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.extend_from_slice(block);
+            }
+            Message::Port(port) => dst.put_u16(*port),
+            Message::Extended { id, payload } => {
+                dst.put_u8(*id);
+                dst.extend_from_slice(payload);
+            }
         }
+    }
 
-        if len_u > MAX {
+    /// Build a message from its tag and payload, checking the payload has the size the tag calls for.
+    fn parse(tag: MessageTag, mut payload: &[u8]) -> Result<Self, std::io::Error> {
+        let expected = match tag {
+            MessageTag::Choke | MessageTag::Unchoke | MessageTag::Interested | MessageTag::NotInterested => Some(0),
+            MessageTag::Have => Some(4),
+            MessageTag::Request | MessageTag::Cancel => Some(12),
+            MessageTag::Port => Some(2),
+            MessageTag::Bitfield | MessageTag::Piece | MessageTag::Extended => None,
+        };
+        let minimum = match tag {
+            MessageTag::Piece => 8,
+            MessageTag::Extended => 1,
+            _ => 0,
+        };
+        let actual = payload.len();
+        let error = match (expected, minimum) {
+            (Some(expected), _) if actual != expected => Some(ParseError::Length { expected, actual }),
+            (_, expected) if actual < expected => Some(ParseError::TooShort { expected, actual }),
+            _ => None,
+        };
+        if let Some(error) = error {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", len),
+                format!("{tag:?} message: {error}"),
             ));
         }
 
-        if src.len() < 5 {
-            //not enough to read the tag
-            return Ok(None);
-        }
+        Ok(match tag {
+            MessageTag::Choke => Message::Choke,
+            MessageTag::Unchoke => Message::Unchoke,
+            MessageTag::Interested => Message::Interested,
+            MessageTag::NotInterested => Message::NotInterested,
+            MessageTag::Have => Message::Have(payload.get_u32()),
+            MessageTag::Bitfield => Message::Bitfield(Bitfield::from_payload(payload.to_vec())),
+            MessageTag::Request => Message::Request {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            MessageTag::Piece => Message::Piece {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                block: payload.to_vec(),
+            },
+            MessageTag::Cancel => Message::Cancel {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            MessageTag::Port => Message::Port(payload.get_u16()),
+            MessageTag::Extended => Message::Extended {
+                id: payload.get_u8(),
+                payload: payload.to_vec(),
+            },
+        })
+    }
+}
+
+/// Codec of peer-wire messages.
+///
+/// Frames longer than `max_frame` (tag included) are rejected both ways. The default fits a full
+/// block and the bitfield of a torrent of up to half a million pieces; use
+/// [`MessageFramer::for_pieces`] to size it after a given torrent.
+pub struct MessageFramer {
+    max_frame: usize,
+}
 
-        if src.len() < 4 + len_u {
-            // The full string has not yet arrived.
-            //
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + len_u - src.len());
+const MAX: usize = 1 << 16;
 
-            // We inform the Framed that we need more bytes to form the next
-            // frame.
-            return Ok(None);
-        }
+impl MessageFramer {
+    pub fn with_max_frame(max_frame: usize) -> Self {
+        Self { max_frame }
+    }
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let tag = src[4];
-        let data = if src.len() > 5 {
-            src[5..4 + len_u].to_vec()
-        } else {
-            vec![]
-        };
+    /// A framer accepting the bitfield of a torrent made of `npieces` pieces
+    pub fn for_pieces(npieces: usize) -> Self {
+        Self::with_max_frame(usize::max(MAX, npieces.div_ceil(8) + 1))
+    }
+}
 
-        src.advance(4 + len_u);
-        Ok(Some(Message {
-            length: len,
-            tag: MessageTag::from_repr(tag)
-                .context("Constructing MessageTag from u8 repr")
-                .expect("Unknown message tag"),
-            payload: data,
-        }))
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self::with_max_frame(MAX)
+    }
+}
+
+impl Decoder for MessageFramer {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Frames with an unknown tag are skipped, hence the loop
+        loop {
+            if src.len() < 4 {
+                return Ok(None);
+            }
+
+            // Read length marker.
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let len = u32::from_be_bytes(length_bytes);
+            let len_u = len as usize;
+
+            if len == 0 {
+                //this is a heartbeat message
+                src.advance(4);
+                return Ok(Some(Message::KeepAlive));
+            }
+
+            if len_u > self.max_frame {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Frame of length {} is too large.", len),
+                ));
+            }
+
+            if src.len() < 4 + len_u {
+                // The full string has not yet arrived.
+                //
+                // We reserve more space in the buffer. This is not strictly
+                // necessary, but is a good idea performance-wise.
+                src.reserve(4 + len_u - src.len());
+
+                // We inform the Framed that we need more bytes to form the next
+                // frame.
+                return Ok(None);
+            }
+
+            let tag = MessageTag::from_repr(src[4]);
+            let message = tag.map(|tag| Message::parse(tag, &src[5..4 + len_u]));
+
+            // Use advance to modify src such that it no longer contains
+            // this frame.
+            src.advance(4 + len_u);
+            match message {
+                Some(message) => return message.map(Some),
+                None => continue, // Unknown message, e.g. from an extension we did not negotiate
+            }
+        }
     }
 }
 
@@ -100,26 +255,234 @@ impl Encoder<Message> for MessageFramer {
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // Don't send a string if it is longer than the other end will
         // accept.
-        let len = item.payload.len() + 1;
-        if len > MAX {
-            // Never forget the tag byte (+1)
+        let len = match item {
+            Message::KeepAlive => 0,
+            _ => item.payload_len() + 1, // Never forget the tag byte (+1)
+        };
+        if len > self.max_frame {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", len),
             ));
         }
 
-        // Convert the length into a byte array.
-        // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_le_bytes((len as u32).try_into().unwrap());
+        // Convert the length into a big-endian byte array.
+        // The cast to u32 cannot overflow as frames are bounded by `max_frame`.
+        let len_slice = u32::to_be_bytes(len as u32);
 
         // Reserve space in the buffer.
         dst.reserve(4 + len);
 
-        // Write the length and string to the buffer.
+        // Write the length, tag and payload to the buffer.
         dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.tag as u8);
-        dst.extend_from_slice(&item.payload);
+        if let Some(tag) = item.tag() {
+            dst.put_u8(tag as u8);
+        }
+        item.put_payload(dst);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CASES;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_bytes(rng: &mut StdRng, max_len: usize) -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    /// A frame with a small length prefix, a likely tag and a payload whose size may not match the
+    /// prefix, so that random input reaches past the length check
+    fn random_frame(rng: &mut StdRng) -> Vec<u8> {
+        let mut frame = rng.gen_range(0u32..24).to_be_bytes().to_vec();
+        frame.push(rng.gen_range(0..=21));
+        frame.extend(random_bytes(rng, 24));
+        frame
+    }
+
+    fn encode(message: Message) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        MessageFramer::default().encode(message, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn parse_any_payload() {
+        let mut rng = StdRng::seed_from_u64(28);
+        for _ in 0..CASES {
+            let Some(tag) = MessageTag::from_repr(rng.gen_range(0..=21)) else {
+                continue;
+            };
+            let payload = random_bytes(&mut rng, 20);
+            // Whatever parses is exactly what was received
+            if let Ok(message) = Message::parse(tag, &payload) {
+                assert_eq!(message.tag(), Some(tag));
+                assert_eq!(&encode(message)[5..], payload.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn decode_any_input() {
+        let mut rng = StdRng::seed_from_u64(29);
+        for case in 0..CASES {
+            let input = if case % 2 == 0 {
+                random_bytes(&mut rng, 64)
+            } else {
+                (0..rng.gen_range(1..4)).flat_map(|_| random_frame(&mut rng)).collect()
+            };
+            let mut framer = MessageFramer::with_max_frame(32);
+            let mut src = BytesMut::from(input.as_slice());
+            // Every call either consumes a frame, waits for more bytes or fails
+            loop {
+                let before = src.len();
+                match framer.decode(&mut src) {
+                    Ok(Some(message)) => {
+                        assert!(src.len() < before);
+                        assert!(message.wire_len() <= 4 + 32);
+                    }
+                    Ok(None) => {
+                        assert!(src.len() < 4 || src.len() < 4 + u32::from_be_bytes(src[..4].try_into().unwrap()) as usize);
+                        break;
+                    }
+                    Err(e) => {
+                        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_truncated_frames() {
+        let mut rng = StdRng::seed_from_u64(30);
+        let messages = [
+            Message::Have(7),
+            Message::Request { index: 1, begin: 2, length: 3 },
+            Message::Piece { index: 1, begin: 0, block: random_bytes(&mut rng, 32) },
+            Message::Port(6881),
+            Message::Extended { id: 0, payload: b"de".to_vec() },
+        ];
+        for message in messages {
+            let frame = encode(message.clone());
+            for len in 0..frame.len() {
+                let mut src = BytesMut::from(&frame[..len]);
+                assert!(MessageFramer::default().decode(&mut src).unwrap().is_none());
+                assert_eq!(src.len(), len, "a truncated frame is left for more bytes to arrive");
+            }
+            let mut src = frame;
+            assert_eq!(MessageFramer::default().decode(&mut src).unwrap(), Some(message));
+        }
+    }
+
+    #[test]
+    fn round_trip_every_variant() {
+        let mut bitfield = Bitfield::new(12);
+        bitfield.set(3).unwrap();
+        let messages = [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(u32::MAX),
+            Message::Bitfield(bitfield),
+            Message::Request { index: 1, begin: 1 << 14, length: 1 << 14 },
+            Message::Piece { index: 2, begin: 0, block: vec![0xab; 1 << 14] },
+            Message::Piece { index: 2, begin: 0, block: Vec::new() },
+            Message::Cancel { index: 1, begin: 1 << 14, length: 1 << 14 },
+            Message::Port(6881),
+            Message::Extended { id: 1, payload: b"d5:added0:e".to_vec() },
+            Message::Extended { id: 0, payload: Vec::new() },
+        ];
+        let mut src = BytesMut::new();
+        for message in &messages {
+            let frame = encode(message.clone());
+            assert_eq!(frame.len(), message.wire_len(), "{message:?}");
+            src.extend_from_slice(&frame);
+        }
+        let mut framer = MessageFramer::default();
+        for message in messages {
+            let decoded = framer.decode(&mut src).unwrap();
+            // A bitfield comes back with its length rounded to whole bytes until checked
+            let decoded = match decoded {
+                Some(Message::Bitfield(bitfield)) => Some(Message::Bitfield(bitfield.checked(12).unwrap())),
+                decoded => decoded,
+            };
+            assert_eq!(decoded, Some(message));
+        }
+        assert!(src.is_empty());
+        assert_eq!(framer.decode(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn keep_alive_flood() {
+        let mut src = BytesMut::from(&[0u8; 4 * 10_000][..]);
+        src.extend_from_slice(&encode(Message::Have(1)));
+        let mut framer = MessageFramer::default();
+        for _ in 0..10_000 {
+            assert_eq!(framer.decode(&mut src).unwrap(), Some(Message::KeepAlive));
+        }
+        assert_eq!(framer.decode(&mut src).unwrap(), Some(Message::Have(1)));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn unknown_tags_are_skipped() {
+        let mut src = BytesMut::new();
+        for tag in [10, 13, 255] {
+            src.put_u32(4);
+            src.put_u8(tag);
+            src.extend_from_slice(&[1, 2, 3]);
+        }
+        src.extend_from_slice(&encode(Message::Unchoke));
+        let mut framer = MessageFramer::default();
+        assert_eq!(framer.decode(&mut src).unwrap(), Some(Message::Unchoke));
+        assert!(src.is_empty());
+        // An unknown frame still arriving is waited for, not skipped blindly
+        let mut src = BytesMut::new();
+        src.put_u32(4);
+        src.put_u8(10);
+        assert_eq!(framer.decode(&mut src).unwrap(), None);
+        assert_eq!(src.len(), 5);
+    }
+
+    #[test]
+    fn frames_over_max_frame_are_rejected() {
+        let max_frame = 64;
+        let mut framer = MessageFramer::with_max_frame(max_frame);
+        // Tag and 8 bytes of index and begin around the block
+        let fitting = Message::Piece { index: 0, begin: 0, block: vec![0; max_frame - 9] };
+        let over = Message::Piece { index: 0, begin: 0, block: vec![0; max_frame - 8] };
+
+        let mut dst = BytesMut::new();
+        framer.encode(fitting.clone(), &mut dst).unwrap();
+        assert_eq!(framer.decode(&mut dst).unwrap(), Some(fitting));
+        let error = framer.encode(over.clone(), &mut dst).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(dst.is_empty(), "nothing written for a rejected frame");
+
+        // Rejected as soon as the length prefix arrives, without waiting for the frame
+        let mut src = encode(over);
+        src.truncate(4);
+        let error = framer.decode(&mut src).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn invalid_payload_sizes_are_errors() {
+        for (tag, len) in [(MessageTag::Choke, 1), (MessageTag::Have, 3), (MessageTag::Request, 13), (MessageTag::Port, 1), (MessageTag::Piece, 7), (MessageTag::Extended, 0)] {
+            let mut src = BytesMut::new();
+            src.put_u32(len as u32 + 1);
+            src.put_u8(tag as u8);
+            src.extend_from_slice(&vec![0; len]);
+            let error = MessageFramer::default().decode(&mut src).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{tag:?} of {len} bytes");
+        }
+    }
+}
@@ -1,7 +1,11 @@
+use anyhow::Context;
 use peers::Peers;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
-pub const PEER_ID: &'static str = "00112233445566778899"; // This peer_id is artificial, it is used for getting the peer_id's of other peers during handshake.
+pub const PEER_ID: &str = "00112233445566778899"; // This peer_id is artificial, it is used for getting the peer_id's of other peers during handshake.
 
 #[derive(Debug, Serialize)]
 pub struct TrackerSend {
@@ -11,6 +15,17 @@ pub struct TrackerSend {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+}
+
+/// Transition of a download told to the tracker, regular announces having none
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -20,7 +35,14 @@ pub struct TrackerResponse {
     pub peers: Peers,
 }
 
-#[repr(C)] // Consider a HandShake instance as a byte array for easier writing to peer via TCP connection
+/// Size of a handshake on the wire
+pub const HANDSHAKE_LEN: usize = 68;
+/// Time a peer has to complete its handshake, whichever side connected
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Reserved bytes of our handshakes, telling support for the extension protocol (BEP 10)
+const RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HandShake {
     pub len: u8,
     pub bittorrent: [u8; 19],
@@ -29,77 +51,84 @@ pub struct HandShake {
     pub peer_id: [u8; 20],
 }
 
+/// Errors raised when parsing peer-wire structures from bytes
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("expected {expected} bytes, got {actual}")]
+    Length { expected: usize, actual: usize },
+    #[error("expected at least {expected} bytes, got {actual}")]
+    TooShort { expected: usize, actual: usize },
+    #[error("unsupported protocol string {0:?}")]
+    Protocol(String),
+}
+
 impl HandShake {
     pub fn new(hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Self {
             len: 19,
             bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved: RESERVED,
             sha_hash: hash,
             peer_id,
         }
     }
 
-    /// A Handshake as a mut byte slice
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()]; // Raw pointer, pointing to an array of bytes (handshake)
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes }; // Back to reference, for safety reasons
-        bytes
-    }
-}
-
-pub struct Request {
-    index: [u8; 4],
-    begin: [u8; 4],
-    length: [u8; 4],
-}
-
-impl Request {
-    pub fn new(index: u32, begin: u32, length: u32) -> Self {
-        Self {
-            index: u32::to_be_bytes(index),
-            begin: u32::to_be_bytes(begin),
-            length: u32::to_be_bytes(length),
-        }
-    }
-
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
-
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+    /// Whether the peer supports the extension protocol
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
     }
 
-    pub fn length(&self) -> u32 {
-        u32::from_be_bytes(self.length)
+    /// Serialize the handshake as it is sent on the wire
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        bytes[0] = self.len;
+        bytes[1..20].copy_from_slice(&self.bittorrent);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.sha_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()]; // Raw pointer
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes }; // Not a raw pointer anymore
-        bytes
+    /// Parse a handshake received from a peer, checking its length and protocol string
+    pub fn parse(bytes: &[u8]) -> Result<Self, ParseError> {
+        let bytes: &[u8; HANDSHAKE_LEN] = bytes.try_into().map_err(|_| ParseError::Length {
+            expected: HANDSHAKE_LEN,
+            actual: bytes.len(),
+        })?;
+        let handshake = Self {
+            len: bytes[0],
+            bittorrent: bytes[1..20].try_into().expect("19 bytes"),
+            reserved: bytes[20..28].try_into().expect("8 bytes"),
+            sha_hash: bytes[28..48].try_into().expect("20 bytes"),
+            peer_id: bytes[48..68].try_into().expect("20 bytes"),
+        };
+        if handshake.len != 19 || &handshake.bittorrent != b"BitTorrent protocol" {
+            return Err(ParseError::Protocol(
+                String::from_utf8_lossy(&handshake.bittorrent).into_owned(),
+            ));
+        }
+        Ok(handshake)
     }
 }
 
-pub struct Piece {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: [u8],
+/// Send our handshake for `info_hash` to a freshly connected peer and read its own back
+pub async fn handshake(stream: &mut TcpStream, info_hash: [u8; 20]) -> anyhow::Result<HandShake> {
+    send_handshake(stream, info_hash).await?;
+    let handshake = read_handshake(stream).await?;
+    anyhow::ensure!(handshake.sha_hash == info_hash, "peer serves another torrent");
+    Ok(handshake)
 }
 
-impl Piece {
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
-
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
-    }
+pub async fn send_handshake(stream: &mut TcpStream, info_hash: [u8; 20]) -> anyhow::Result<()> {
+    let handshake = HandShake::new(info_hash, *PEER_ID.as_bytes().first_chunk().expect("20 byte peer id"));
+    stream.write_all(&handshake.to_bytes()).await.context("writing handshake via TCP to peer")
+}
 
-    pub fn block(&self) -> &[u8] {
-        &self.block
-    }
+/// Read the handshake of a peer, which on incoming connections tells the torrent it wants
+pub async fn read_handshake(stream: &mut TcpStream) -> anyhow::Result<HandShake> {
+    let mut bytes = [0; HANDSHAKE_LEN];
+    stream.read_exact(&mut bytes).await.context("reading handshake from peer")?;
+    HandShake::parse(&bytes).context("parsing handshake from peer")
 }
 
 pub mod peers {
@@ -127,7 +156,7 @@ pub mod peers {
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
+            if !v.len().is_multiple_of(6) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
 
@@ -155,12 +184,61 @@ pub mod peers {
     }
 }
 
-///
+/// Percent-encode every byte of a 20 bytes hash, for tracker URLs
 pub fn url_encode(t: &[u8; 20]) -> String {
     let mut encoded = String::with_capacity(3 * t.len());
     for &byte in t {
         encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+        encoded.push_str(&hex::encode([byte]));
     }
     encoded
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CASES;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn parse_round_trip() {
+        let handshake = HandShake::new([1; 20], [2; 20]);
+        assert!(handshake.supports_extensions());
+        assert_eq!(HandShake::parse(&handshake.to_bytes()).unwrap(), handshake);
+    }
+
+    #[test]
+    fn parse_any_bytes() {
+        let mut rng = StdRng::seed_from_u64(28);
+        let valid = HandShake::new([1; 20], [2; 20]).to_bytes();
+        for _ in 0..CASES {
+            let len = rng.gen_range(0..=HANDSHAKE_LEN + 4);
+            let mut bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            // Keep a valid header in some of them, for the rest of the parsing to be reached
+            if rng.gen() {
+                let header = usize::min(len, 20);
+                bytes[..header].copy_from_slice(&valid[..header]);
+            }
+            match HandShake::parse(&bytes) {
+                Ok(handshake) => assert_eq!(handshake.to_bytes().as_slice(), bytes.as_slice()),
+                Err(ParseError::Length { expected, actual }) => {
+                    assert_eq!((expected, actual), (HANDSHAKE_LEN, len));
+                }
+                Err(ParseError::Protocol(_)) => assert_ne!(&bytes[..20], &valid[..20]),
+                Err(e) => panic!("unexpected error {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_truncated() {
+        let valid = HandShake::new([1; 20], [2; 20]).to_bytes();
+        for len in 0..HANDSHAKE_LEN {
+            assert!(matches!(HandShake::parse(&valid[..len]), Err(ParseError::Length { actual, .. }) if actual == len));
+        }
+        let mut bytes = valid;
+        bytes[1] = b'b';
+        assert!(matches!(HandShake::parse(&bytes), Err(ParseError::Protocol(_))));
+    }
+}
@@ -0,0 +1,292 @@
+use crate::bitfield::Bitfield;
+use crate::download::Block;
+use crate::message::Message;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Number of requests kept in flight per peer.
+const PIPELINE: usize = 5;
+/// Number of requests from a peer queued before further ones get ignored
+const UPLOAD_QUEUE: usize = 256;
+
+/// State of a connection to a peer, updated by every message exchanged with it.
+///
+/// Peers are free to send messages in any order: a bitfield may be omitted or come after `Have`s,
+/// and a choke may arrive while blocks are in flight, in which case the peer discards them.
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// Pieces owned by the peer
+    pub bitfield: Bitfield,
+    /// Whether the peer let one of our requests time out, in which case it is only trusted with
+    /// one request at a time until it delivers again
+    pub snubbed: bool,
+    /// Blocks requested from the peer and not received yet, with the time of the request
+    in_flight: Vec<(Block, Instant)>,
+    /// Blocks the peer requested from us and not sent yet, oldest first
+    uploads: VecDeque<Block>,
+}
+
+/// State transitions and data reported by [`PeerState::handle`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The peer choked us, discarding the blocks requested from it
+    Choked { dropped: Vec<Block> },
+    Unchoked,
+    Interested,
+    NotInterested,
+    /// The peer announced a new piece
+    Have(usize),
+    /// The peer sent a bitfield, replacing what it announced before: pieces may be added, and in
+    /// the case of a second bitfield dropped
+    Bitfield { added: Vec<usize>, dropped: Vec<usize> },
+    /// A block we requested arrived
+    Block { block: Block, data: Vec<u8> },
+    /// The peer requested a block from us
+    Requested(Block),
+    /// The peer cancelled one of its requests
+    Cancelled(Block),
+    /// A message of the extension protocol (BEP 10)
+    Extended { id: u8, payload: Vec<u8> },
+}
+
+impl PeerState {
+    /// State of a fresh connection: both sides choking and not interested
+    pub fn new(npieces: usize) -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            bitfield: Bitfield::new(npieces),
+            snubbed: false,
+            in_flight: Vec::new(),
+            uploads: VecDeque::new(),
+        }
+    }
+
+    /// Update the state after a message from the peer
+    pub fn handle(&mut self, message: Message) -> anyhow::Result<Option<PeerEvent>> {
+        let event = match message {
+            Message::KeepAlive | Message::Port(_) => return Ok(None),
+            Message::Extended { id, payload } => PeerEvent::Extended { id, payload },
+            Message::Choke => {
+                if self.peer_choking {
+                    return Ok(None);
+                }
+                self.peer_choking = true;
+                PeerEvent::Choked { dropped: self.in_flight.drain(..).map(|(b, _)| b).collect() }
+            }
+            Message::Unchoke => {
+                if !self.peer_choking {
+                    return Ok(None);
+                }
+                self.peer_choking = false;
+                PeerEvent::Unchoked
+            }
+            Message::Interested => {
+                self.peer_interested = true;
+                PeerEvent::Interested
+            }
+            Message::NotInterested => {
+                self.peer_interested = false;
+                PeerEvent::NotInterested
+            }
+            Message::Have(index) => {
+                let index = index as usize;
+                if self.bitfield.has(index) {
+                    return Ok(None);
+                }
+                self.bitfield.set(index)?;
+                PeerEvent::Have(index)
+            }
+            Message::Bitfield(bitfield) => {
+                let bitfield = bitfield.checked(self.bitfield.piece_count())?;
+                let pieces = 0..bitfield.piece_count();
+                let added = pieces.clone().filter(|&i| bitfield.has(i) && !self.bitfield.has(i)).collect();
+                let dropped = pieces.filter(|&i| !bitfield.has(i) && self.bitfield.has(i)).collect();
+                self.bitfield = bitfield;
+                PeerEvent::Bitfield { added, dropped }
+            }
+            Message::Piece { index, begin, block: data } => {
+                let Some(pos) = self
+                    .in_flight
+                    .iter()
+                    .position(|(b, _)| (b.piece, b.begin) == (index, begin))
+                else {
+                    return Ok(None); // Not requested, or cancelled meanwhile
+                };
+                let (block, _) = self.in_flight.swap_remove(pos);
+                self.snubbed = false;
+                PeerEvent::Block { block, data }
+            }
+            Message::Request { index, begin, length } => {
+                let block = Block { piece: index, begin, length };
+                // Requests made while choked are dropped, as the peer knows it will not be served
+                if self.am_choking || self.uploads.len() >= UPLOAD_QUEUE || self.uploads.contains(&block) {
+                    return Ok(None);
+                }
+                self.uploads.push_back(block);
+                PeerEvent::Requested(block)
+            }
+            Message::Cancel { index, begin, length } => {
+                let block = Block { piece: index, begin, length };
+                let Some(pos) = self.uploads.iter().position(|b| *b == block) else {
+                    return Ok(None); // Already sent
+                };
+                self.uploads.remove(pos);
+                PeerEvent::Cancelled(block)
+            }
+        };
+        Ok(Some(event))
+    }
+
+    /// Whether another block can be requested from the peer
+    pub fn can_request(&self) -> bool {
+        let pipeline = if self.snubbed { 1 } else { PIPELINE };
+        self.am_interested && !self.peer_choking && self.in_flight.len() < pipeline
+    }
+
+    /// Record a request for `block`, returning the message to send
+    pub fn request(&mut self, block: Block) -> Message {
+        self.in_flight.push((block, Instant::now()));
+        Message::Request { index: block.piece, begin: block.begin, length: block.length }
+    }
+
+    /// Forget a request for `block`, returning the `Cancel` to send if it was in flight
+    pub fn cancel(&mut self, block: Block) -> Option<Message> {
+        let pos = self.in_flight.iter().position(|(b, _)| *b == block)?;
+        self.in_flight.swap_remove(pos);
+        Some(Message::Cancel { index: block.piece, begin: block.begin, length: block.length })
+    }
+
+    /// Give up on the requests pending for longer than `timeout`, marking the peer as snubbed if any
+    pub fn timed_out(&mut self, timeout: Duration) -> Vec<Block> {
+        let mut expired = Vec::new();
+        self.in_flight.retain(|&(block, requested)| {
+            let pending = requested.elapsed() < timeout;
+            if !pending {
+                expired.push(block);
+            }
+            pending
+        });
+        if !expired.is_empty() {
+            self.snubbed = true;
+        }
+        expired
+    }
+
+    /// Update our interest in the peer, returning the message to send if it changed
+    pub fn set_interested(&mut self, interested: bool) -> Option<Message> {
+        if self.am_interested == interested {
+            return None;
+        }
+        self.am_interested = interested;
+        Some(if interested { Message::Interested } else { Message::NotInterested })
+    }
+
+    /// Choke or unchoke the peer, returning the message to send if it changed. Choking discards
+    /// the requests of the peer.
+    pub fn set_choking(&mut self, choking: bool) -> Option<Message> {
+        if self.am_choking == choking {
+            return None;
+        }
+        self.am_choking = choking;
+        if choking {
+            self.uploads.clear();
+        }
+        Some(if choking { Message::Choke } else { Message::Unchoke })
+    }
+
+    pub fn has_uploads(&self) -> bool {
+        !self.uploads.is_empty()
+    }
+
+    /// Take the oldest block requested by the peer, to be sent to it
+    pub fn next_upload(&mut self) -> Option<Block> {
+        self.uploads.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(8);
+        for &piece in pieces {
+            bitfield.set(piece).unwrap();
+        }
+        bitfield
+    }
+
+    fn block(piece: u32, begin: u32) -> Block {
+        Block { piece, begin, length: 16 }
+    }
+
+    fn piece(block: Block) -> Message {
+        Message::Piece { index: block.piece, begin: block.begin, block: vec![0; block.length as usize] }
+    }
+
+    #[test]
+    fn have_before_bitfield() {
+        let mut state = PeerState::new(8);
+        assert_eq!(state.handle(Message::Have(3)).unwrap(), Some(PeerEvent::Have(3)));
+        assert_eq!(state.handle(Message::Have(3)).unwrap(), None);
+        let event = state.handle(Message::Bitfield(bitfield(&[1, 3]))).unwrap();
+        assert_eq!(event, Some(PeerEvent::Bitfield { added: vec![1], dropped: vec![] }));
+        assert!(state.handle(Message::Have(8)).is_err());
+    }
+
+    #[test]
+    fn second_bitfield_replaces_the_first() {
+        let mut state = PeerState::new(8);
+        state.handle(Message::Bitfield(bitfield(&[0, 1]))).unwrap();
+        let event = state.handle(Message::Bitfield(bitfield(&[1, 2]))).unwrap();
+        assert_eq!(event, Some(PeerEvent::Bitfield { added: vec![2], dropped: vec![0] }));
+        assert_eq!(state.bitfield.as_bytes(), bitfield(&[1, 2]).as_bytes());
+    }
+
+    #[test]
+    fn choke_mid_piece_returns_the_blocks_in_flight() {
+        let mut state = PeerState::new(8);
+        assert_eq!(state.set_interested(true), Some(Message::Interested));
+        assert!(!state.can_request());
+        assert_eq!(state.handle(Message::Unchoke).unwrap(), Some(PeerEvent::Unchoked));
+        for begin in [0, 16, 32] {
+            assert!(state.can_request());
+            state.request(block(5, begin));
+        }
+
+        let data = vec![0; 16];
+        let event = state.handle(piece(block(5, 16))).unwrap();
+        assert_eq!(event, Some(PeerEvent::Block { block: block(5, 16), data }));
+        let Some(PeerEvent::Choked { mut dropped }) = state.handle(Message::Choke).unwrap() else {
+            panic!("choke not reported");
+        };
+        dropped.sort_by_key(|block| block.begin);
+        assert_eq!(dropped, [block(5, 0), block(5, 32)]);
+        assert!(!state.can_request());
+        assert_eq!(state.handle(Message::Choke).unwrap(), None);
+        // Blocks arriving after the choke were not requested anymore
+        assert_eq!(state.handle(piece(block(5, 0))).unwrap(), None);
+        assert_eq!(state.cancel(block(5, 32)), None);
+    }
+
+    #[test]
+    fn requests_while_choking_are_dropped() {
+        let mut state = PeerState::new(8);
+        let request = Message::Request { index: 1, begin: 0, length: 16 };
+        assert_eq!(state.handle(request.clone()).unwrap(), None);
+        assert!(!state.has_uploads());
+
+        assert_eq!(state.set_choking(false), Some(Message::Unchoke));
+        assert_eq!(state.handle(request.clone()).unwrap(), Some(PeerEvent::Requested(block(1, 0))));
+        assert_eq!(state.handle(request).unwrap(), None);
+        assert_eq!(state.set_choking(true), Some(Message::Choke));
+        assert_eq!(state.next_upload(), None);
+    }
+}
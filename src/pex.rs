@@ -0,0 +1,242 @@
+use crate::dht::krpc::{decode_peer, encode_peer};
+use crate::message::Message;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Extended message id of the extension handshake (BEP 10)
+const HANDSHAKE_ID: u8 = 0;
+/// Extended message id peers send us `ut_pex` messages with, as told in our extension handshake
+const UT_PEX_ID: u8 = 1;
+/// Interval between two messages to a peer
+pub const INTERVAL: Duration = Duration::from_secs(60);
+/// Messages arriving sooner after the previous one are ignored, with some slack for the timers
+/// of the peer
+const MIN_INTERVAL: Duration = Duration::from_secs(45);
+/// Most peers added, and dropped, in one message
+const MAX_PEERS: usize = 50;
+
+// Flags of added peers. Those telling uTP (0x04) and holepunch (0x08) support are never set, as
+// we only know of TCP connections.
+
+/// The peer prefers encrypted connections
+pub const ENCRYPTION: u8 = 0x01;
+/// The peer is a seed
+pub const SEED: u8 = 0x02;
+/// The peer accepted an outgoing connection, so is reachable
+pub const REACHABLE: u8 = 0x10;
+
+/// The extension handshake, telling the extended message ids of the extensions a peer supports
+/// (0 disabling one) and the port it listens on
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Handshake {
+    #[serde(default)]
+    m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    p: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    v: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<i64>,
+}
+
+/// A `ut_pex` message: the compact addresses of the peers connected to and disconnected from since
+/// the previous message, with one byte of flags per added peer
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Update {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default, rename = "added.f")]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+/// What [`PexState::handle`] learnt from an extended message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PexEvent {
+    /// The extension handshake of the peer, with the port it listens on and its flags
+    Handshake { port: Option<u16>, flags: u8 },
+    /// Peers the peer got connected to, with their flags
+    Added(Vec<(SocketAddr, u8)>),
+}
+
+/// Our extension handshake, offering `ut_pex` and telling the port we listen on
+pub fn handshake(port: u16) -> anyhow::Result<Message> {
+    let handshake = Handshake {
+        m: BTreeMap::from([("ut_pex".to_string(), i64::from(UT_PEX_ID))]),
+        p: Some(port),
+        v: Some(ByteBuf::from(format!("Rottorrent {}", env!("CARGO_PKG_VERSION")))),
+        e: None,
+    };
+    Ok(Message::Extended { id: HANDSHAKE_ID, payload: serde_bencode::to_bytes(&handshake)? })
+}
+
+/// Peer exchange (BEP 11) with one peer: the peers it was told about, and the pace of its messages
+#[derive(Debug, Default)]
+pub struct PexState {
+    /// Extended message id of `ut_pex` for the peer, once it told it supports it
+    id: Option<u8>,
+    /// Peers sent to the peer and not dropped since, with their flags
+    sent: HashMap<SocketAddr, u8>,
+    /// When the last message of the peer arrived
+    received: Option<Instant>,
+}
+
+impl PexState {
+    /// Handle an extended message from the peer
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> anyhow::Result<Option<PexEvent>> {
+        match id {
+            HANDSHAKE_ID => {
+                let handshake: Handshake = serde_bencode::from_bytes(payload)?;
+                // Later handshakes only update what they mention
+                if let Some(&id) = handshake.m.get("ut_pex") {
+                    self.id = u8::try_from(id).ok().filter(|&id| id != 0);
+                }
+                let flags = if handshake.e == Some(1) { ENCRYPTION } else { 0 };
+                Ok(Some(PexEvent::Handshake { port: handshake.p.filter(|&p| p != 0), flags }))
+            }
+            UT_PEX_ID => {
+                if self.received.is_some_and(|received| received.elapsed() < MIN_INTERVAL) {
+                    return Ok(None); // Flooding
+                }
+                self.received = Some(Instant::now());
+                let update: Update = serde_bencode::from_bytes(payload)?;
+                let mut added: Vec<_> = decode(&update.added, 6, &update.added_flags).take(MAX_PEERS).collect();
+                added.extend(decode(&update.added6, 18, &update.added6_flags).take(MAX_PEERS));
+                Ok(Some(PexEvent::Added(added)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The message telling the peer how `swarm`, the peers we are connected to with their flags,
+    /// changed since the previous one, if it supports peer exchange and anything changed
+    pub fn next(&mut self, swarm: &HashMap<SocketAddr, u8>) -> anyhow::Result<Option<Message>> {
+        let Some(id) = self.id else {
+            return Ok(None);
+        };
+        let mut update = Update::default();
+        let mut counts = [0; 4]; // Added and dropped, for each family
+        let added: Vec<_> = swarm.iter().filter(|(addr, _)| !self.sent.contains_key(addr)).collect();
+        for (&addr, &flags) in added {
+            let (peers, peer_flags, count) = match addr.ip() {
+                IpAddr::V4(_) => (&mut update.added, &mut update.added_flags, &mut counts[0]),
+                IpAddr::V6(_) => (&mut update.added6, &mut update.added6_flags, &mut counts[1]),
+            };
+            if *count < MAX_PEERS {
+                *count += 1;
+                peers.extend(encode_peer(addr));
+                peer_flags.push(flags);
+                self.sent.insert(addr, flags);
+            }
+        }
+        let dropped: Vec<_> = self.sent.keys().filter(|addr| !swarm.contains_key(addr)).copied().collect();
+        for addr in dropped {
+            let (peers, count) = match addr.ip() {
+                IpAddr::V4(_) => (&mut update.dropped, &mut counts[2]),
+                IpAddr::V6(_) => (&mut update.dropped6, &mut counts[3]),
+            };
+            if *count < MAX_PEERS {
+                *count += 1;
+                peers.extend(encode_peer(addr));
+                self.sent.remove(&addr);
+            }
+        }
+        if counts == [0; 4] {
+            return Ok(None);
+        }
+        Ok(Some(Message::Extended { id, payload: serde_bencode::to_bytes(&update)? }))
+    }
+}
+
+/// Compact addresses of `len` bytes each, with their flags, missing flags being 0
+fn decode<'a>(bytes: &'a [u8], len: usize, flags: &'a [u8]) -> impl Iterator<Item = (SocketAddr, u8)> + 'a {
+    bytes.chunks_exact(len).enumerate().filter_map(move |(i, peer)| {
+        let addr = decode_peer(peer).filter(|addr| addr.port() != 0)?;
+        Some((addr, flags.get(i).copied().unwrap_or(0)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(message: Message) -> (u8, Vec<u8>) {
+        let Message::Extended { id, payload } = message else {
+            panic!("not an extended message: {message:?}");
+        };
+        (id, payload)
+    }
+
+    /// Two sides having exchanged their extension handshakes
+    fn connected() -> (PexState, PexState) {
+        let (mut ours, mut theirs) = (PexState::default(), PexState::default());
+        let (id, handshake) = payload(handshake(6881).unwrap());
+        assert_eq!(theirs.handle(id, &handshake).unwrap(), Some(PexEvent::Handshake { port: Some(6881), flags: 0 }));
+        ours.handle(id, &handshake).unwrap();
+        (ours, theirs)
+    }
+
+    fn update(ours: &mut PexState, swarm: &HashMap<SocketAddr, u8>) -> Update {
+        serde_bencode::from_bytes(&payload(ours.next(swarm).unwrap().unwrap()).1).unwrap()
+    }
+
+    #[test]
+    fn nothing_sent_without_ut_pex() {
+        let mut state = PexState::default();
+        let swarm = HashMap::from([(SocketAddr::from(([10, 0, 0, 1], 1000)), 0)]);
+        assert!(state.next(&swarm).unwrap().is_none());
+    }
+
+    #[test]
+    fn added_and_dropped_are_capped() {
+        let (mut ours, mut theirs) = connected();
+        let mut swarm: HashMap<SocketAddr, u8> =
+            (0..120).map(|i| (SocketAddr::from(([10, 0, 0, 1], 1000 + i)), REACHABLE)).collect();
+        swarm.insert("[::1]:5".parse().unwrap(), SEED);
+
+        let (id, message) = payload(ours.next(&swarm).unwrap().unwrap());
+        let Some(PexEvent::Added(added)) = theirs.handle(id, &message).unwrap() else {
+            panic!("no peers added");
+        };
+        assert_eq!(added.len(), MAX_PEERS + 1);
+        assert!(added.iter().all(|(addr, flags)| swarm[addr] == *flags));
+        // Too soon after the previous message
+        assert_eq!(theirs.handle(id, &message).unwrap(), None);
+
+        let next = update(&mut ours, &swarm);
+        assert_eq!((next.added.len(), next.added6.len()), (6 * MAX_PEERS, 0));
+        let next = update(&mut ours, &swarm);
+        assert_eq!((next.added.len(), next.added_flags.len()), (6 * 20, 20));
+        assert!(ours.next(&swarm).unwrap().is_none());
+
+        swarm.clear();
+        let next = update(&mut ours, &swarm);
+        assert_eq!((next.added.len(), next.dropped.len(), next.dropped6.len()), (0, 6 * MAX_PEERS, 18));
+        update(&mut ours, &swarm);
+        update(&mut ours, &swarm);
+        assert!(ours.next(&swarm).unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_flags_and_port_zero() {
+        let (_, mut theirs) = connected();
+        let update = Update {
+            added: ByteBuf::from([10, 0, 0, 1, 0, 80, 10, 0, 0, 2, 0, 0, 10, 0, 0, 3, 0, 81, 1]),
+            added_flags: ByteBuf::from([SEED]),
+            ..Update::default()
+        };
+        let event = theirs.handle(UT_PEX_ID, &serde_bencode::to_bytes(&update).unwrap()).unwrap();
+        let expected = vec![(SocketAddr::from(([10, 0, 0, 1], 80)), SEED), (SocketAddr::from(([10, 0, 0, 3], 81)), 0)];
+        assert_eq!(event, Some(PexEvent::Added(expected)));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::storage::Storage;
+use crate::{Keys, Torrent};
+use std::str::FromStr;
+
+/// Download priority of a file, and of the pieces overlapping it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Priority {
+    /// Not downloaded
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Designates files of a torrent, by index or by a glob on their path
+#[derive(Debug, Clone)]
+pub enum FileSelector {
+    Index(usize),
+    Glob(glob::Pattern),
+}
+
+impl FileSelector {
+    pub fn matches(&self, index: usize, path: &str) -> bool {
+        match self {
+            FileSelector::Index(i) => *i == index,
+            FileSelector::Glob(pattern) => pattern.matches(path),
+        }
+    }
+}
+
+impl FromStr for FileSelector {
+    type Err = glob::PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(index) => Ok(FileSelector::Index(index)),
+            Err(_) => Ok(FileSelector::Glob(glob::Pattern::new(s)?)),
+        }
+    }
+}
+
+/// A `SELECTOR=PRIORITY` argument
+#[derive(Debug, Clone)]
+pub struct FilePriority {
+    pub selector: FileSelector,
+    pub priority: Priority,
+}
+
+impl FromStr for FilePriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (selector, priority) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected SELECTOR=PRIORITY, got {s:?}"))?;
+        Ok(Self {
+            selector: selector.parse().map_err(|e| format!("{e}"))?,
+            priority: clap::ValueEnum::from_str(priority, true)?,
+        })
+    }
+}
+
+/// Paths of the files of a torrent, relative to its root and `/`-separated
+pub fn file_paths(torrent: &Torrent) -> Vec<String> {
+    match &torrent.info.keys {
+        Keys::SingleFile { .. } => vec![torrent.info.name.clone()],
+        Keys::MultiFile { files } => files.iter().map(|file| file.path.join("/")).collect(),
+    }
+}
+
+/// Priority of each file of a torrent.
+///
+/// With `only` selectors, the files they do not match are skipped. `priorities` then apply in
+/// order, later ones overriding earlier ones. Fails if a selector matches no file.
+pub fn file_priorities(
+    torrent: &Torrent,
+    only: &[FileSelector],
+    priorities: &[FilePriority],
+) -> anyhow::Result<Vec<Priority>> {
+    let paths = file_paths(torrent);
+    check_matches(&paths, only.iter().chain(priorities.iter().map(|p| &p.selector)))?;
+    Ok(paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| {
+            let mut priority = if only.is_empty() || only.iter().any(|s| s.matches(index, path)) {
+                Priority::Normal
+            } else {
+                Priority::Skip
+            };
+            for p in priorities.iter().filter(|p| p.selector.matches(index, path)) {
+                priority = p.priority;
+            }
+            priority
+        })
+        .collect())
+}
+
+/// Which files to download sequentially: all of them, or those matching `selectors`. Fails if a
+/// selector matches no file.
+pub fn file_sequential(torrent: &Torrent, all: bool, selectors: &[FileSelector]) -> anyhow::Result<Vec<bool>> {
+    let paths = file_paths(torrent);
+    check_matches(&paths, selectors)?;
+    Ok(paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| all || selectors.iter().any(|s| s.matches(index, path)))
+        .collect())
+}
+
+/// Fail on the first selector matching none of the files at `paths`, most likely a mistake
+fn check_matches<'a>(paths: &[String], selectors: impl IntoIterator<Item = &'a FileSelector>) -> anyhow::Result<()> {
+    for selector in selectors {
+        if !paths.iter().enumerate().any(|(index, path)| selector.matches(index, path)) {
+            match selector {
+                FileSelector::Index(index) => anyhow::bail!("no file {index}, the torrent has {} files", paths.len()),
+                FileSelector::Glob(pattern) => anyhow::bail!("no file of the torrent matches {pattern}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How a piece is to be picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PiecePlan {
+    pub index: usize,
+    pub priority: Priority,
+    /// Pieces of sequential files are picked in order, instead of rarest first
+    pub sequential: bool,
+}
+
+impl From<usize> for PiecePlan {
+    fn from(index: usize) -> Self {
+        Self {
+            index,
+            priority: Priority::Normal,
+            sequential: false,
+        }
+    }
+}
+
+/// Plan the pieces overlapping wanted files, each piece taking the highest priority among the
+/// files it overlaps, and being sequential if any of its wanted files is.
+pub fn piece_plan(storage: &Storage, npieces: usize, priorities: &[Priority], sequential: &[bool]) -> Vec<PiecePlan> {
+    (0..npieces)
+        .map(|index| {
+            let spans = storage
+                .spans(index, 0, storage.piece_size(index))
+                .expect("whole piece");
+            PiecePlan {
+                index,
+                priority: spans.iter().map(|span| priorities[span.file]).max().unwrap_or(Priority::Skip),
+                sequential: spans
+                    .iter()
+                    .any(|span| priorities[span.file] != Priority::Skip && sequential[span.file]),
+            }
+        })
+        .filter(|plan| plan.priority != Priority::Skip)
+        .collect()
+}
+
+/// Rank of a candidate piece, lower being picked first: the readahead window of sequential files
+/// comes first, then higher priorities. Within a priority, sequential pieces go in order and the
+/// others rarest first.
+pub fn rank(plan: &PiecePlan, in_window: bool, availability: usize) -> impl Ord {
+    (
+        !in_window,
+        std::cmp::Reverse(plan.priority),
+        if plan.sequential { 0 } else { availability },
+        plan.index,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::path::Path;
+
+    fn selectors(selectors: &[&str]) -> Vec<FileSelector> {
+        selectors.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn selection_by_index_and_glob() {
+        let (mut torrent, _) = testing::torrent(&[1, 1, 1], 16);
+        let Keys::MultiFile { files } = &mut torrent.info.keys else { unreachable!() };
+        files[0].path = vec!["video".to_string(), "a.mkv".to_string()];
+        files[1].path = vec!["b.srt".to_string()];
+        files[2].path = vec!["video".to_string(), "c.mkv".to_string()];
+
+        let priorities = file_priorities(&torrent, &selectors(&["video/*.mkv"]), &[]).unwrap();
+        assert_eq!(priorities, [Priority::Normal, Priority::Skip, Priority::Normal]);
+        let overrides: Vec<FilePriority> = ["*.mkv=low", "2=high"].iter().map(|p| p.parse().unwrap()).collect();
+        let priorities = file_priorities(&torrent, &selectors(&["0", "1"]), &overrides).unwrap();
+        assert_eq!(priorities, [Priority::Low, Priority::Normal, Priority::High]);
+        assert_eq!(file_sequential(&torrent, false, &selectors(&["*.srt"])).unwrap(), [false, true, false]);
+        assert_eq!(file_sequential(&torrent, true, &[]).unwrap(), [true; 3]);
+    }
+
+    #[test]
+    fn selectors_matching_nothing_are_errors() {
+        let (torrent, _) = testing::torrent(&[1, 1, 1], 16);
+        assert!(file_priorities(&torrent, &selectors(&["3"]), &[]).is_err());
+        assert!(file_priorities(&torrent, &selectors(&["*.mkv"]), &[]).is_err());
+        assert!(file_priorities(&torrent, &[], &["z=high".parse().unwrap()]).is_err());
+        assert!(file_sequential(&torrent, false, &selectors(&["7"])).is_err());
+    }
+
+    #[test]
+    fn pieces_take_the_highest_priority_of_their_files() {
+        // Pieces 0-1 in a, 2 straddling a and b, 3 in b, 4 straddling b and c
+        let (torrent, _) = testing::torrent(&[40, 30, 10], 16);
+        let storage = Storage::new(&torrent.info, Path::new("/nonexistent")).unwrap();
+        let plan = |priorities: &[Priority], sequential: &[bool]| piece_plan(&storage, 5, priorities, sequential);
+
+        let plans = plan(&[Priority::Skip, Priority::Low, Priority::Skip], &[true, false, true]);
+        let expected = |index, priority| PiecePlan { index, priority, sequential: false };
+        assert_eq!(plans, [expected(2, Priority::Low), expected(3, Priority::Low), expected(4, Priority::Low)]);
+
+        let plans = plan(&[Priority::High, Priority::Skip, Priority::Normal], &[false, true, false]);
+        let priorities: Vec<_> = plans.iter().map(|plan| (plan.index, plan.priority)).collect();
+        assert_eq!(priorities, [(0, Priority::High), (1, Priority::High), (2, Priority::High), (4, Priority::Normal)]);
+        // Skipped files do not make a piece sequential
+        assert!(plans.iter().all(|plan| !plan.sequential));
+    }
+
+    /// Indices of candidate pieces, given as (plan, in the window, availability), in picking order
+    fn picking_order(candidates: &[(PiecePlan, bool, usize)]) -> Vec<usize> {
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by_key(|(plan, in_window, availability)| rank(plan, *in_window, *availability));
+        candidates.iter().map(|(plan, _, _)| plan.index).collect()
+    }
+
+    fn plan(index: usize, priority: Priority, sequential: bool) -> PiecePlan {
+        PiecePlan { index, priority, sequential }
+    }
+
+    #[test]
+    fn readahead_window_comes_first() {
+        let candidates = [
+            (plan(0, Priority::High, false), false, 1),
+            (plan(5, Priority::Low, true), true, 9),
+            (plan(4, Priority::Low, true), true, 9),
+            (plan(6, Priority::Low, true), false, 0),
+            (plan(1, Priority::Normal, false), false, 1),
+        ];
+        // The window in order whatever its priority, then by priority
+        assert_eq!(picking_order(&candidates), [4, 5, 0, 1, 6]);
+    }
+
+    #[test]
+    fn sequential_files_among_others() {
+        let candidates = [
+            (plan(9, Priority::Normal, true), false, 5),
+            (plan(0, Priority::Normal, false), false, 3),
+            (plan(1, Priority::Normal, false), false, 1),
+            (plan(7, Priority::Normal, true), false, 2),
+            (plan(2, Priority::Normal, false), false, 0),
+            (plan(3, Priority::High, false), false, 8),
+        ];
+        // Within a priority, sequential pieces go in order as if the rarest, the others rarest first
+        assert_eq!(picking_order(&candidates), [3, 2, 7, 9, 1, 0]);
+    }
+}
@@ -0,0 +1,213 @@
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Caps in bytes per second for each direction, `None` meaning unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rates {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl Rates {
+    fn get(&self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Upload => self.upload,
+            Direction::Download => self.download,
+        }
+    }
+}
+
+/// Caps applying to all the traffic, to each torrent and to each peer connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSet {
+    pub global: Rates,
+    pub torrent: Rates,
+    pub peer: Rates,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Token bucket refilled at the rate given on each acquisition, so that limits can change at any
+/// time. Up to a second worth of tokens accumulates while idle.
+///
+/// Acquisitions larger than the tokens available leave the bucket in debt and wait for it to be
+/// paid off, so that frames larger than the rate still go through and concurrent users queue up.
+#[derive(Debug)]
+struct Bucket {
+    state: Mutex<(f64, Instant)>, // Tokens, and when they were last refilled
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// How long to wait before `bytes` may be transferred at `rate`
+    fn take(&self, rate: Option<u64>, bytes: usize) -> Duration {
+        let mut state = self.state.lock().expect("bucket lock poisoned");
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        let Some(rate) = rate.filter(|&rate| rate > 0) else {
+            *tokens = 0.0;
+            *last = now;
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        *tokens = f64::min(rate, *tokens + now.duration_since(*last).as_secs_f64() * rate);
+        *last = now;
+        *tokens -= bytes as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / rate)
+        }
+    }
+}
+
+/// A bucket for each direction
+#[derive(Debug)]
+struct Buckets {
+    upload: Bucket,
+    download: Bucket,
+}
+
+impl Buckets {
+    fn new() -> Self {
+        Self {
+            upload: Bucket::new(),
+            download: Bucket::new(),
+        }
+    }
+
+    fn get(&self, direction: Direction) -> &Bucket {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+}
+
+/// Bandwidth shared by every torrent of the session, under the limit set currently active
+#[derive(Debug)]
+pub struct Bandwidth {
+    limits: Mutex<LimitSet>,
+    global: Buckets,
+}
+
+impl Bandwidth {
+    pub fn new(limits: LimitSet) -> Arc<Self> {
+        Arc::new(Self {
+            limits: Mutex::new(limits),
+            global: Buckets::new(),
+        })
+    }
+
+    /// Switch to another set of limits, taking effect on the next transfers
+    pub fn set_limits(&self, limits: LimitSet) {
+        *self.limits.lock().expect("bandwidth lock poisoned") = limits;
+    }
+
+    pub fn limits(&self) -> LimitSet {
+        *self.limits.lock().expect("bandwidth lock poisoned")
+    }
+
+    pub fn torrent(self: &Arc<Self>) -> TorrentBandwidth {
+        TorrentBandwidth {
+            session: Arc::clone(self),
+            buckets: Arc::new(Buckets::new()),
+        }
+    }
+}
+
+/// Bandwidth of a torrent, shared by its peer connections
+#[derive(Debug, Clone)]
+pub struct TorrentBandwidth {
+    session: Arc<Bandwidth>,
+    buckets: Arc<Buckets>,
+}
+
+impl TorrentBandwidth {
+    pub fn peer(&self) -> PeerBandwidth {
+        PeerBandwidth {
+            torrent: self.clone(),
+            buckets: Buckets::new(),
+        }
+    }
+}
+
+/// Bandwidth of a peer connection
+#[derive(Debug)]
+pub struct PeerBandwidth {
+    torrent: TorrentBandwidth,
+    buckets: Buckets,
+}
+
+impl PeerBandwidth {
+    /// Wait until `bytes` may be transferred in `direction` under the peer, torrent and global caps
+    pub async fn acquire(&self, direction: Direction, bytes: usize) {
+        let limits = self.torrent.session.limits();
+        let wait = [
+            self.buckets.get(direction).take(limits.peer.get(direction), bytes),
+            self.torrent.buckets.get(direction).take(limits.torrent.get(direction), bytes),
+            self.torrent.session.global.get(direction).take(limits.global.get(direction), bytes),
+        ]
+        .into_iter()
+        .max()
+        .expect("three scopes");
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bucket left idle for `idle`
+    fn idle_bucket(idle: Duration) -> Bucket {
+        Bucket {
+            state: Mutex::new((0.0, Instant::now() - idle)),
+        }
+    }
+
+    fn assert_near(wait: Duration, expected: f64) {
+        let wait = wait.as_secs_f64();
+        assert!(wait <= expected && wait > expected - 0.05, "waiting {wait}s instead of {expected}s");
+    }
+
+    #[test]
+    fn idle_buckets_hold_a_second_worth_of_tokens() {
+        let bucket = idle_bucket(Duration::from_secs(10));
+        assert_eq!(bucket.take(Some(1000), 1000), Duration::ZERO);
+        assert_near(bucket.take(Some(1000), 500), 0.5);
+    }
+
+    #[test]
+    fn acquisitions_wait_for_the_debt_to_be_paid_off() {
+        let bucket = idle_bucket(Duration::from_secs(1));
+        // Larger than the rate, and than the tokens available
+        assert_near(bucket.take(Some(1000), 3000), 2.0);
+        assert_near(bucket.take(Some(1000), 1000), 3.0);
+        assert_near(bucket.take(Some(2000), 0), 1.5);
+    }
+
+    #[test]
+    fn unlimited_rates_clear_tokens_and_debt() {
+        let bucket = idle_bucket(Duration::from_secs(1));
+        assert_near(bucket.take(Some(1000), 5000), 4.0);
+        assert_eq!(bucket.take(None, 5000), Duration::ZERO);
+        assert_eq!(bucket.take(Some(0), 5000), Duration::ZERO);
+        // Limited again, starting with neither debt nor a burst
+        assert_near(bucket.take(Some(1000), 500), 0.5);
+    }
+}
@@ -0,0 +1,282 @@
+use crate::bitfield::Bitfield;
+use crate::storage::Storage;
+use crate::Torrent;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Progress of a download, as saved to and restored from a resume file
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Verified pieces
+    pub have: Bitfield,
+    /// Blocks written to disk of the pieces not verified yet
+    pub partial: Vec<(usize, Vec<bool>)>,
+    pub stats: TrackerStats,
+}
+
+/// Transfer counters reported to the tracker
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct TrackerStats {
+    pub uploaded: usize,
+    pub downloaded: usize,
+}
+
+/// Fast-resume state of a torrent, stored bencoded next to its data
+#[derive(Debug, Deserialize, Serialize)]
+struct ResumeFile {
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+    pieces: ByteBuf, // Bitfield of the verified pieces
+    partial: Vec<PartialPiece>,
+    files: Vec<FileStamp>,
+    #[serde(default)]
+    partfile: Option<FileStamp>,
+    #[serde(default)]
+    skipped: Option<ByteBuf>, // Bitmap of the files whose data was in the partfile
+    stats: TrackerStats,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PartialPiece {
+    index: usize,
+    blocks: ByteBuf, // Bitmap of the blocks written to disk
+}
+
+/// Size and modification time of a file, to detect changes behind our back
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct FileStamp {
+    length: u64,
+    mtime: u64, // In nanoseconds since the epoch, 0 for a missing file
+}
+
+/// Location of the resume file of a torrent downloaded to `root`
+pub fn path(root: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    root.join(".rottorrent").join(format!("{}.resume", hex::encode(info_hash)))
+}
+
+/// Restore the progress of a download from its resume file.
+///
+/// The resume file is trusted if the files it describes, and the partfile, are unchanged on disk.
+/// Otherwise, or when there is no resume file, every piece is checked against its hash.
+pub async fn restore(torrent: &Torrent, storage: &Storage, path: &Path) -> anyhow::Result<Progress> {
+    let npieces = torrent.info.pieces.0.len();
+    match load(torrent, storage, path).await {
+        Ok(Some(progress)) => {
+            eprintln!("Resuming with {}/{npieces} pieces", progress.have.count());
+            return Ok(progress);
+        }
+        Ok(None) => {}
+        Err(e) => eprintln!("Ignoring resume file {}: {e:#}", path.display()),
+    }
+
+    let mut have = Bitfield::new(npieces);
+    for index in 0..npieces {
+        if verify(torrent, storage, index).await {
+            have.set(index)?;
+        }
+    }
+    if have.count() > 0 {
+        eprintln!("Recheck found {}/{npieces} pieces on disk", have.count());
+    }
+    Ok(Progress {
+        have,
+        partial: Vec::new(),
+        stats: TrackerStats::default(),
+    })
+}
+
+/// Whether the data of a piece on disk matches its hash
+async fn verify(torrent: &Torrent, storage: &Storage, index: usize) -> bool {
+    let Ok(data) = storage.read_piece(index).await else {
+        return false; // Missing or short file
+    };
+    let hash: [u8; 20] = Sha1::digest(&data).into();
+    hash == torrent.info.pieces.0[index]
+}
+
+/// Load the resume file, returning `None` if there is none or the files changed since it was saved
+async fn load(torrent: &Torrent, storage: &Storage, path: &Path) -> anyhow::Result<Option<Progress>> {
+    let content = match tokio::fs::read(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("read resume file"),
+    };
+    let resume: ResumeFile = serde_bencode::from_bytes(&content).context("decode resume file")?;
+    anyhow::ensure!(resume.info_hash.as_slice() == torrent.info_hash(), "resume file of another torrent");
+    if resume.files != stamp(storage).await? || resume.partfile != Some(stamp_file(storage.partfile()).await?) {
+        eprintln!("Files changed since the resume file was saved, rechecking");
+        return Ok(None);
+    }
+
+    let Some(skipped) = resume.skipped else {
+        eprintln!("Resume file without the skipped files, rechecking");
+        return Ok(None);
+    };
+    let nfiles = storage.files().len();
+    let skipped = Bitfield::from_payload(skipped.into_vec()).checked(nfiles)?;
+    // Files selected or skipped since the save have their data read from the other of their file
+    // and the partfile, which lacks it
+    let moved: Vec<bool> = (0..nfiles).map(|file| skipped.has(file) != storage.skipped()[file]).collect();
+
+    let npieces = torrent.info.pieces.0.len();
+    let mut have = Bitfield::from_payload(resume.pieces.into_vec()).checked(npieces)?;
+    let mut stale = Vec::new();
+    for index in 0..npieces {
+        let spans = storage.spans(index, 0, storage.piece_size(index))?;
+        if spans.iter().any(|span| moved[span.file]) {
+            stale.push(index);
+        }
+    }
+    if !stale.is_empty() {
+        eprintln!("File selection changed, rechecking {} pieces", stale.len());
+    }
+    for &index in &stale {
+        if have.has(index) && !verify(torrent, storage, index).await {
+            have.clear(index)?;
+        }
+    }
+
+    let mut partial = Vec::new();
+    for piece in resume.partial {
+        anyhow::ensure!(piece.index < npieces && !have.has(piece.index), "invalid partial piece {}", piece.index);
+        if stale.contains(&piece.index) {
+            continue; // Its blocks are not where they are read from anymore
+        }
+        let nblocks = storage.piece_size(piece.index).div_ceil(crate::download::BLOCK_MAX);
+        let blocks = Bitfield::from_payload(piece.blocks.into_vec()).checked(nblocks)?;
+        partial.push((piece.index, (0..nblocks).map(|i| blocks.has(i)).collect()));
+    }
+    Ok(Some(Progress {
+        have,
+        partial,
+        stats: resume.stats,
+    }))
+}
+
+/// Save the progress of a download, along with the current state of its files
+pub async fn save(torrent: &Torrent, storage: &Storage, path: &Path, progress: &Progress) -> anyhow::Result<()> {
+    let mut partial = Vec::new();
+    for (index, blocks) in &progress.partial {
+        let mut bitmap = Bitfield::new(blocks.len());
+        for (i, _) in blocks.iter().enumerate().filter(|(_, &written)| written) {
+            bitmap.set(i)?;
+        }
+        partial.push(PartialPiece {
+            index: *index,
+            blocks: ByteBuf::from(bitmap.as_bytes()),
+        });
+    }
+    let mut skipped = Bitfield::new(storage.files().len());
+    for (file, _) in storage.skipped().iter().enumerate().filter(|(_, &skipped)| skipped) {
+        skipped.set(file)?;
+    }
+    let resume = ResumeFile {
+        info_hash: ByteBuf::from(torrent.info_hash()),
+        pieces: ByteBuf::from(progress.have.as_bytes()),
+        partial,
+        files: stamp(storage).await?,
+        partfile: Some(stamp_file(storage.partfile()).await?),
+        skipped: Some(ByteBuf::from(skipped.as_bytes())),
+        stats: progress.stats,
+    };
+    let content = serde_bencode::to_bytes(&resume).context("encode resume file")?;
+
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await.context("create resume directory")?;
+    }
+    // Write then rename, so that an interrupted save leaves the previous file intact
+    let tmp = path.with_extension("resume.tmp");
+    tokio::fs::write(&tmp, content).await.context("write resume file")?;
+    tokio::fs::rename(&tmp, path).await.context("replace resume file")?;
+    Ok(())
+}
+
+async fn stamp(storage: &Storage) -> anyhow::Result<Vec<FileStamp>> {
+    let mut stamps = Vec::new();
+    for file in storage.files() {
+        stamps.push(stamp_file(&file.path).await?);
+    }
+    Ok(stamps)
+}
+
+async fn stamp_file(path: &Path) -> anyhow::Result<FileStamp> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) => Ok(FileStamp {
+            length: metadata.len(),
+            mtime: metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |mtime| mtime.as_nanos() as u64),
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(FileStamp { length: 0, mtime: 0 }),
+        Err(e) => Err(e).with_context(|| format!("stat {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::picker::Priority;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn trusts_an_unchanged_selection() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = testing::torrent(&[10, 10], 16); // The first piece straddles both files
+        let mut storage = Storage::new(&torrent.info, dir.path()).unwrap();
+        storage.skip_files(&[Priority::Normal, Priority::Skip]);
+        storage.write_block(0, 0, &data[..16]).await.unwrap();
+        let path = path(dir.path(), &torrent.info_hash());
+        let mut have = Bitfield::new(2);
+        have.set(0).unwrap();
+        let progress = Progress { have, partial: Vec::new(), stats: TrackerStats::default() };
+        save(&torrent, &storage, &path, &progress).await.unwrap();
+
+        let restored = load(&torrent, &storage, &path).await.unwrap().unwrap();
+        assert!(restored.have.has(0));
+    }
+
+    #[tokio::test]
+    async fn rechecks_pieces_of_files_selected_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = testing::torrent(&[10, 10], 16); // The first piece straddles both files
+        let mut storage = Storage::new(&torrent.info, dir.path()).unwrap();
+        storage.skip_files(&[Priority::Normal, Priority::Skip]);
+        storage.write_block(0, 0, &data[..16]).await.unwrap();
+        let path = path(dir.path(), &torrent.info_hash());
+        let mut have = Bitfield::new(2);
+        have.set(0).unwrap();
+        let progress = Progress { have, partial: vec![(1, vec![true])], stats: TrackerStats::default() };
+        save(&torrent, &storage, &path, &progress).await.unwrap();
+
+        // The part of the first piece in `b` is in the partfile, not where it is read from now
+        let storage = Storage::new(&torrent.info, dir.path()).unwrap();
+        let restored = load(&torrent, &storage, &path).await.unwrap().unwrap();
+        assert_eq!(restored.have.count(), 0);
+        assert!(restored.partial.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rechecks_when_the_partfile_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = testing::torrent(&[10, 10], 16);
+        let mut storage = Storage::new(&torrent.info, dir.path()).unwrap();
+        storage.skip_files(&[Priority::Normal, Priority::Skip]);
+        storage.write_block(0, 0, &data[..16]).await.unwrap();
+        let path = path(dir.path(), &torrent.info_hash());
+        let mut have = Bitfield::new(2);
+        have.set(0).unwrap();
+        let progress = Progress { have, partial: Vec::new(), stats: TrackerStats::default() };
+        save(&torrent, &storage, &path, &progress).await.unwrap();
+
+        tokio::fs::write(storage.partfile(), [0; 20]).await.unwrap();
+        assert!(load(&torrent, &storage, &path).await.unwrap().is_none());
+        save(&torrent, &storage, &path, &progress).await.unwrap();
+        tokio::fs::remove_file(storage.partfile()).await.unwrap();
+        assert!(load(&torrent, &storage, &path).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,272 @@
+use crate::ratelimit::{Bandwidth, LimitSet};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Interval between two checks of the schedule
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+
+    fn previous(self) -> Self {
+        Self::ALL[(self as usize + 6) % 7]
+    }
+}
+
+/// A time of day, in minutes since midnight, written `HH:MM`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(u16);
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s
+            .split_once(':')
+            .and_then(|(hours, minutes)| Some((hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?)));
+        match parsed {
+            // 24:00 closes a window at the end of the day
+            Some((hours, minutes)) if (hours < 24 && minutes < 60) || (hours, minutes) == (24, 0) => {
+                Ok(Self(hours * 60 + minutes))
+            }
+            _ => Err(format!("expected a time as HH:MM, got {s:?}")),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// A moment of the week, to the minute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WeekTime {
+    pub day: Weekday,
+    pub time: TimeOfDay,
+}
+
+/// A weekly period during which the scheduled limits apply, e.g. weekdays from 09:00 to 18:00.
+///
+/// A window ending before it starts runs past midnight into the next day.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Window {
+    /// Days the window starts on, every day when empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+}
+
+impl Window {
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn contains(&self, now: WeekTime) -> bool {
+        let (from, to, time) = (self.from.0, self.to.0, now.time.0);
+        if from <= to {
+            self.starts_on(now.day) && from <= time && time < to
+        } else {
+            (self.starts_on(now.day) && from <= time) || (self.starts_on(now.day.previous()) && time < to)
+        }
+    }
+}
+
+/// Source of the current time of the week, replaceable to drive the scheduler in tests
+pub trait Clock {
+    fn now(&self) -> WeekTime;
+}
+
+/// The local time of the system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn now(&self) -> WeekTime {
+        // SAFETY: `time` accepts a null pointer, and `tm` is plain data written by `localtime_r`
+        let tm = unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            assert!(!libc::localtime_r(&now, &mut tm).is_null(), "local time unavailable");
+            tm
+        };
+        WeekTime {
+            day: Weekday::ALL[(tm.tm_wday as usize + 6) % 7], // tm_wday counts from Sunday
+            time: TimeOfDay((tm.tm_hour * 60 + tm.tm_min) as u16 % MINUTES_PER_DAY),
+        }
+    }
+}
+
+/// Picks the limits in force at a given time
+#[derive(Debug, Clone)]
+pub struct Scheduler<C> {
+    pub regular: LimitSet,
+    pub scheduled: LimitSet,
+    pub windows: Vec<Window>,
+    pub clock: C,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn limits(&self) -> LimitSet {
+        let now = self.clock.now();
+        if self.windows.iter().any(|window| window.contains(now)) {
+            self.scheduled
+        } else {
+            self.regular
+        }
+    }
+
+    /// Keep the limits of `bandwidth` in line with the schedule
+    pub async fn run(self, bandwidth: Arc<Bandwidth>) {
+        let mut check = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            check.tick().await;
+            let limits = self.limits();
+            if limits != bandwidth.limits() {
+                eprintln!(
+                    "Switching to the {} bandwidth limits",
+                    if limits == self.regular { "regular" } else { "scheduled" }
+                );
+                bandwidth.set_limits(limits);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::Rates;
+    use std::cell::Cell;
+
+    /// A clock showing whatever time the test sets
+    struct FakeClock(Cell<WeekTime>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> WeekTime {
+            self.0.get()
+        }
+    }
+
+    fn at(day: Weekday, time: &str) -> WeekTime {
+        WeekTime { day, time: time.parse().unwrap() }
+    }
+
+    fn window(days: &[Weekday], from: &str, to: &str) -> Window {
+        Window { days: days.to_vec(), from: from.parse().unwrap(), to: to.parse().unwrap() }
+    }
+
+    #[test]
+    fn time_of_day() {
+        assert_eq!("00:00".parse(), Ok(TimeOfDay(0)));
+        assert_eq!("23:59".parse(), Ok(TimeOfDay(MINUTES_PER_DAY - 1)));
+        assert_eq!("24:00".parse(), Ok(TimeOfDay(MINUTES_PER_DAY)));
+        for invalid in ["24:01", "12:60", "12", "1:2:3", "-1:00", ""] {
+            assert!(invalid.parse::<TimeOfDay>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let office = window(&[Weekday::Mon, Weekday::Fri], "09:00", "18:00");
+        assert!(office.contains(at(Weekday::Mon, "09:00")));
+        assert!(office.contains(at(Weekday::Fri, "17:59")));
+        assert!(!office.contains(at(Weekday::Mon, "18:00")));
+        assert!(!office.contains(at(Weekday::Mon, "08:59")));
+        assert!(!office.contains(at(Weekday::Tue, "12:00")));
+    }
+
+    #[test]
+    fn window_past_midnight() {
+        let night = window(&[Weekday::Sun], "22:00", "06:00");
+        assert!(night.contains(at(Weekday::Sun, "22:00")));
+        assert!(night.contains(at(Weekday::Sun, "23:59")));
+        // The end of the window falls on the next day, the week wrapping around
+        assert!(night.contains(at(Weekday::Mon, "00:00")));
+        assert!(night.contains(at(Weekday::Mon, "05:59")));
+        assert!(!night.contains(at(Weekday::Mon, "06:00")));
+        assert!(!night.contains(at(Weekday::Mon, "22:30")));
+        assert!(!night.contains(at(Weekday::Sun, "05:00")));
+        assert!(!night.contains(at(Weekday::Sat, "23:00")));
+    }
+
+    #[test]
+    fn window_until_the_end_of_the_day() {
+        let evening = window(&[Weekday::Sat], "20:00", "24:00");
+        assert!(evening.contains(at(Weekday::Sat, "20:00")));
+        assert!(evening.contains(at(Weekday::Sat, "23:59")));
+        assert!(!evening.contains(at(Weekday::Sun, "00:00")));
+        assert!(!evening.contains(at(Weekday::Sat, "19:59")));
+    }
+
+    #[test]
+    fn window_without_days_applies_every_day() {
+        let lunch = window(&[], "12:00", "13:00");
+        let night = window(&[], "23:00", "01:00");
+        for day in Weekday::ALL {
+            assert!(lunch.contains(at(day, "12:30")));
+            assert!(!lunch.contains(at(day, "13:00")));
+            assert!(night.contains(at(day, "23:30")));
+            assert!(night.contains(at(day, "00:30")));
+            assert!(!night.contains(at(day, "01:00")));
+        }
+    }
+
+    #[test]
+    fn scheduler_switches_limits() {
+        let scheduled = LimitSet {
+            global: Rates { upload: Some(1000), download: None },
+            ..LimitSet::default()
+        };
+        let scheduler = Scheduler {
+            regular: LimitSet::default(),
+            scheduled,
+            windows: vec![window(&[Weekday::Wed], "09:00", "17:00"), window(&[], "23:00", "02:00")],
+            clock: FakeClock(Cell::new(at(Weekday::Wed, "08:59"))),
+        };
+        let mut switches = Vec::new();
+        for (day, time) in [
+            (Weekday::Wed, "08:59"),
+            (Weekday::Wed, "09:00"),
+            (Weekday::Wed, "16:59"),
+            (Weekday::Wed, "17:00"),
+            (Weekday::Wed, "23:00"),
+            (Weekday::Thu, "01:59"),
+            (Weekday::Thu, "02:00"),
+            (Weekday::Thu, "09:00"),
+        ] {
+            scheduler.clock.0.set(at(day, time));
+            switches.push(scheduler.limits() == scheduled);
+        }
+        assert_eq!(switches, [false, true, true, false, true, true, false, false]);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::net::{self, HandShake, HANDSHAKE_TIMEOUT};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// Incoming connections queued for a torrent before new ones get dropped
+const BACKLOG: usize = 16;
+/// Pause in accepting connections when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A peer found for one of our torrents
+#[derive(Debug)]
+pub enum NewPeer {
+    /// The peer connected to us, and is handshaken for the torrent
+    Incoming { stream: TcpStream, addr: SocketAddr, handshake: HandShake },
+    /// The peer was found through the DHT, and is to be connected to
+    Discovered(SocketAddr),
+}
+
+/// Registry of the torrents being transferred, which incoming connections are routed to by the
+/// info hash of their handshake
+#[derive(Debug, Default)]
+pub struct Session {
+    torrents: Mutex<HashMap<[u8; 20], mpsc::Sender<NewPeer>>>,
+}
+
+impl Session {
+    /// Register a torrent, returning the receiving end of the peers found for it. The torrent is
+    /// unregistered once the receiver is dropped.
+    pub fn add(&self, info_hash: [u8; 20]) -> mpsc::Receiver<NewPeer> {
+        let (sender, receiver) = mpsc::channel(BACKLOG);
+        self.torrents
+            .lock()
+            .expect("session lock poisoned")
+            .insert(info_hash, sender);
+        receiver
+    }
+
+    fn lookup(&self, info_hash: &[u8; 20]) -> Option<mpsc::Sender<NewPeer>> {
+        let mut torrents = self.torrents.lock().expect("session lock poisoned");
+        match torrents.get(info_hash) {
+            Some(sender) if sender.is_closed() => {
+                torrents.remove(info_hash);
+                None
+            }
+            sender => sender.cloned(),
+        }
+    }
+
+    /// Hand peers found elsewhere to a torrent, returning `false` once it is not registered anymore
+    pub async fn discovered(&self, info_hash: &[u8; 20], peers: impl IntoIterator<Item = SocketAddr>) -> bool {
+        let Some(torrent) = self.lookup(info_hash) else {
+            return false;
+        };
+        for addr in peers {
+            if torrent.send(NewPeer::Discovered(addr)).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Accept peers connecting to `listener`, handing them to the torrent they ask for
+    pub async fn listen(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Accepting peer connection: {e}");
+                    // Out of file descriptors: give the peers connected to a chance to leave
+                    if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                    continue;
+                }
+            };
+            let session = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = session.accept(stream, addr).await {
+                    eprintln!("Incoming peer {addr}: {e:#}");
+                }
+            });
+        }
+    }
+
+    async fn accept(&self, mut stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+        let handshake = timeout(HANDSHAKE_TIMEOUT, net::read_handshake(&mut stream))
+            .await
+            .context("handshake timed out")??;
+        let torrent = self
+            .lookup(&handshake.sha_hash)
+            .with_context(|| format!("unknown torrent {}", hex::encode(handshake.sha_hash)))?;
+        net::send_handshake(&mut stream, handshake.sha_hash).await?;
+        torrent
+            .try_send(NewPeer::Incoming { stream, addr, handshake })
+            .map_err(|_| anyhow::anyhow!("torrent not accepting peers"))
+    }
+}
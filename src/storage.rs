@@ -0,0 +1,390 @@
+use crate::picker::Priority;
+use crate::{Info, Keys};
+use anyhow::Context;
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// A file of the torrent, placed at `offset` in the concatenation of all its files
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// How the files of a torrent get their space on disk before the download
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Allocation {
+    /// Files are created at their final size without reserving their blocks
+    #[default]
+    Sparse,
+    /// Every block of the files is reserved upfront, avoiding fragmentation
+    Full,
+    /// Files grow as blocks get written
+    None,
+}
+
+/// The part of a block that lands in a given file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,   // Index in `Storage::files`
+    pub offset: usize, // Offset in the file
+    pub length: usize,
+}
+
+/// Maps pieces of a torrent onto its files on disk.
+///
+/// Pieces are laid out over the concatenation of the files in the order of the info dictionary,
+/// so a block may span the end of a file and the start of the next ones.
+///
+/// Skipped files are never created: the parts of the pieces straddling them and wanted files are
+/// kept in a sparse partfile instead, at their offset in the whole torrent.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    files: Vec<FileEntry>,
+    skipped: Vec<bool>,
+    partfile: PathBuf,
+    piece_length: usize,
+    length: usize,
+}
+
+impl Storage {
+    /// Lay out the files of `info` under `root`: a single-file torrent is stored as `root/name`, a
+    /// multi-file one under the `root/name` directory. Fails unless there is a hash for every piece.
+    pub fn new(info: &Info, root: &Path) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        match &info.keys {
+            Keys::SingleFile { length } => files.push(FileEntry {
+                path: root.join(checked_component(&info.name)?),
+                offset: 0,
+                length: *length,
+            }),
+            Keys::MultiFile { files: entries } => {
+                let dir = root.join(checked_component(&info.name)?);
+                let mut offset = 0;
+                for file in entries {
+                    anyhow::ensure!(!file.path.is_empty(), "file with an empty path");
+                    let mut path = dir.clone();
+                    for component in &file.path {
+                        path.push(checked_component(component)?);
+                    }
+                    files.push(FileEntry { path, offset, length: file.length });
+                    offset += file.length;
+                }
+            }
+        }
+        let length: usize = files.iter().map(|f| f.length).sum();
+        anyhow::ensure!(info.piece_length > 0, "piece length of 0");
+        let npieces = length.div_ceil(info.piece_length);
+        anyhow::ensure!(
+            info.pieces.0.len() == npieces,
+            "{} piece hashes for the {npieces} pieces of {length} bytes",
+            info.pieces.0.len()
+        );
+        Ok(Self {
+            skipped: vec![false; files.len()],
+            files,
+            partfile: root.join(".rottorrent").join(format!("{}.parts", info.name)),
+            piece_length: info.piece_length,
+            length,
+        })
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    /// Route the data of the files with a `Skip` priority to the partfile
+    pub fn skip_files(&mut self, priorities: &[Priority]) {
+        for (skipped, priority) in self.skipped.iter_mut().zip(priorities) {
+            *skipped = *priority == Priority::Skip;
+        }
+    }
+
+    /// Whether each file is skipped, its data going to the partfile
+    pub fn skipped(&self) -> &[bool] {
+        &self.skipped
+    }
+
+    /// File keeping the data of skipped files, and of the pieces straddling them and wanted files
+    pub fn partfile(&self) -> &Path {
+        &self.partfile
+    }
+
+    /// File and offset in it where a span is stored
+    fn location(&self, span: &Span) -> (&Path, usize) {
+        let file = &self.files[span.file];
+        if self.skipped[span.file] {
+            (&self.partfile, file.offset + span.offset)
+        } else {
+            (&file.path, span.offset)
+        }
+    }
+
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
+    /// Size of a piece, the last one being possibly truncated
+    pub fn piece_size(&self, piece: usize) -> usize {
+        usize::min(self.piece_length, self.length - piece * self.piece_length)
+    }
+
+    /// Give the files their space on disk according to `mode`, creating them and their
+    /// directories. Files already at least as large are left untouched.
+    pub async fn allocate(&self, mode: Allocation) -> anyhow::Result<()> {
+        if mode == Allocation::None {
+            return Ok(());
+        }
+        for entry in self.files.iter().zip(&self.skipped).filter(|(_, &skipped)| !skipped).map(|(entry, _)| entry) {
+            if let Some(dir) = entry.path.parent() {
+                tokio::fs::create_dir_all(dir).await.context("create file directories")?;
+            }
+            let file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&entry.path)
+                .await
+                .with_context(|| format!("open {} for allocation", entry.path.display()))?;
+            let length = entry.length as u64;
+            if file.metadata().await?.len() >= length {
+                continue;
+            }
+            match mode {
+                Allocation::Sparse => file.set_len(length).await?,
+                Allocation::Full => {
+                    let file = file.into_std().await;
+                    tokio::task::spawn_blocking(move || preallocate(&file, length))
+                        .await?
+                        .with_context(|| format!("preallocate {}", entry.path.display()))?;
+                }
+                Allocation::None => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the block at `begin` in `piece` into the spans of the files it covers
+    pub fn spans(&self, piece: usize, begin: usize, length: usize) -> anyhow::Result<Vec<Span>> {
+        anyhow::ensure!(piece * self.piece_length < self.length, "piece {piece} is out of the torrent");
+        anyhow::ensure!(
+            begin + length <= self.piece_size(piece),
+            "block of {length} bytes at offset {begin} is out of piece {piece}"
+        );
+        let start = piece * self.piece_length + begin;
+        let end = start + length;
+        Ok(self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.offset < end && start < f.offset + f.length)
+            .map(|(i, f)| {
+                let from = usize::max(start, f.offset);
+                let to = usize::min(end, f.offset + f.length);
+                Span {
+                    file: i,
+                    offset: from - f.offset,
+                    length: to - from,
+                }
+            })
+            .collect())
+    }
+
+    /// Write a block in place, creating the files and their directories as needed
+    pub async fn write_block(&self, piece: usize, begin: usize, data: &[u8]) -> anyhow::Result<()> {
+        let mut data = data;
+        for span in self.spans(piece, begin, data.len())? {
+            let (path, offset) = self.location(&span);
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await.context("create file directories")?;
+            }
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .await
+                .with_context(|| format!("open {} for writing", path.display()))?;
+            file.seek(SeekFrom::Start(offset as u64)).await?;
+            let (chunk, rest) = data.split_at(span.length);
+            file.write_all(chunk).await.with_context(|| format!("write to {}", path.display()))?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Read a block back from disk
+    pub async fn read_block(&self, piece: usize, begin: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(length);
+        for span in self.spans(piece, begin, length)? {
+            let (path, offset) = self.location(&span);
+            let mut file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("open {} for reading", path.display()))?;
+            file.seek(SeekFrom::Start(offset as u64)).await?;
+            let start = data.len();
+            data.resize(start + span.length, 0);
+            file.read_exact(&mut data[start..])
+                .await
+                .with_context(|| format!("read from {}", path.display()))?;
+        }
+        Ok(data)
+    }
+
+    pub async fn read_piece(&self, piece: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_block(piece, 0, self.piece_size(piece)).await
+    }
+}
+
+/// Reserve the blocks of the first `length` bytes of `file`
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn preallocate(file: &std::fs::File, length: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor is owned by `file`, which outlives the call
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) } {
+        0 => Ok(()),
+        // Not supported by the file system: fall back to writing zeroes
+        libc::EOPNOTSUPP | libc::EINVAL => write_zeroes(file, length),
+        errno => Err(std::io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Reserve the blocks of the first `length` bytes of `file`, by writing zeroes where
+/// `posix_fallocate` is missing, like on macOS
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn preallocate(file: &std::fs::File, length: u64) -> std::io::Result<()> {
+    write_zeroes(file, length)
+}
+
+fn write_zeroes(mut file: &std::fs::File, length: u64) -> std::io::Result<()> {
+    use std::io::{Seek, Write};
+
+    let start = file.seek(SeekFrom::End(0))?;
+    let zeroes = [0; 1 << 16];
+    let mut left = length.saturating_sub(start);
+    while left > 0 {
+        let n = u64::min(left, zeroes.len() as u64) as usize;
+        file.write_all(&zeroes[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+/// Check a path component from the torrent file cannot escape the download directory
+fn checked_component(component: &str) -> anyhow::Result<&str> {
+    let mut components = Path::new(component).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(component),
+        _ => anyhow::bail!("invalid path component {component:?} in torrent"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn rejects_bad_geometry() {
+        let (mut torrent, _) = testing::torrent(&[10, 10], 16);
+        let root = Path::new("/nonexistent");
+        assert!(Storage::new(&torrent.info, root).is_ok());
+        torrent.info.pieces.0.push([0; 20]);
+        assert!(Storage::new(&torrent.info, root).is_err());
+        torrent.info.pieces.0.truncate(1);
+        assert!(Storage::new(&torrent.info, root).is_err());
+        torrent.info.piece_length = 0;
+        torrent.info.pieces.0.clear();
+        assert!(Storage::new(&torrent.info, root).is_err());
+    }
+
+    #[test]
+    fn spans_across_files() {
+        let (torrent, _) = testing::torrent(&[10, 5, 20], 16);
+        let storage = Storage::new(&torrent.info, Path::new("/nonexistent")).unwrap();
+        let span = |file, offset, length| Span { file, offset, length };
+        assert_eq!(storage.piece_size(2), 3);
+        assert_eq!(storage.spans(0, 0, 16).unwrap(), [span(0, 0, 10), span(1, 0, 5), span(2, 0, 1)]);
+        assert_eq!(storage.spans(0, 8, 4).unwrap(), [span(0, 8, 2), span(1, 0, 2)]);
+        assert_eq!(storage.spans(1, 4, 12).unwrap(), [span(2, 5, 12)]);
+        assert_eq!(storage.spans(2, 0, 3).unwrap(), [span(2, 17, 3)]);
+        assert!(storage.spans(2, 0, 4).is_err());
+        assert!(storage.spans(3, 0, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn blocks_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, data) = testing::torrent(&[10, 5, 20], 16);
+        let storage = Storage::new(&torrent.info, dir.path()).unwrap();
+        // Blocks written out of order, the first one spanning three files
+        storage.write_block(1, 0, &data[16..32]).await.unwrap();
+        storage.write_block(0, 0, &data[..16]).await.unwrap();
+        storage.write_block(2, 0, &data[32..]).await.unwrap();
+
+        for (i, piece) in data.chunks(16).enumerate() {
+            assert_eq!(storage.read_piece(i).await.unwrap(), piece);
+        }
+        assert_eq!(storage.read_block(0, 9, 7).await.unwrap(), &data[9..16]);
+        let files = [&data[..10], &data[10..15], &data[15..]];
+        for (entry, content) in storage.files().iter().zip(files) {
+            assert_eq!(tokio::fs::read(&entry.path).await.unwrap(), content);
+        }
+    }
+
+    async fn lengths(storage: &Storage) -> Vec<Option<u64>> {
+        let mut lengths = Vec::new();
+        for entry in storage.files() {
+            lengths.push(tokio::fs::metadata(&entry.path).await.ok().map(|metadata| metadata.len()));
+        }
+        lengths
+    }
+
+    #[tokio::test]
+    async fn allocation_modes() {
+        let (torrent, _) = testing::torrent(&[10, 5, 20], 16);
+        for (mode, expected) in [
+            (Allocation::None, [None, None, None]),
+            (Allocation::Sparse, [Some(10), None, Some(20)]),
+            (Allocation::Full, [Some(10), None, Some(20)]),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut storage = Storage::new(&torrent.info, dir.path()).unwrap();
+            storage.skip_files(&[Priority::Normal, Priority::Skip, Priority::Normal]);
+            storage.allocate(mode).await.unwrap();
+            assert_eq!(lengths(&storage).await, expected, "{mode:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn allocation_keeps_larger_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, _) = testing::torrent(&[10, 5], 16);
+        let storage = Storage::new(&torrent.info, dir.path()).unwrap();
+        tokio::fs::create_dir_all(dir.path().join("t")).await.unwrap();
+        tokio::fs::write(&storage.files()[0].path, [1; 12]).await.unwrap();
+        storage.allocate(Allocation::Full).await.unwrap();
+        assert_eq!(lengths(&storage).await, [Some(12), Some(5)]);
+        assert_eq!(tokio::fs::read(&storage.files()[0].path).await.unwrap(), [1; 12]);
+    }
+
+    #[test]
+    fn zeroes_fill_the_rest_of_the_file() {
+        // What full allocation falls back to where `posix_fallocate` is unsupported
+        let mut file = tempfile::tempfile().unwrap();
+        std::io::Write::write_all(&mut file, b"data").unwrap();
+        write_zeroes(&file, 100_000).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 100_000);
+        write_zeroes(&file, 10).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 100_000);
+
+        let mut content = Vec::new();
+        std::io::Seek::rewind(&mut file).unwrap();
+        std::io::Read::read_to_end(&mut file, &mut content).unwrap();
+        assert_eq!(&content[..4], b"data");
+        assert!(content[4..].iter().all(|&byte| byte == 0));
+    }
+}
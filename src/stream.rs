@@ -0,0 +1,301 @@
+use crate::download::{self, Download};
+use anyhow::Context;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Number of pieces made urgent ahead of the one a reader waits for
+const STREAM_READAHEAD: usize = 4;
+
+/// Files of a torrent being served over HTTP while it downloads
+struct Served {
+    info_hash: String, // Hex-encoded, as in URLs
+    paths: Vec<String>,
+    download: Arc<Mutex<Download>>,
+}
+
+/// Serve the files of a torrent at `http://<addr>/<info hash>/<file path>`.
+///
+/// Single `Range` requests are supported, so that players can seek. The pieces a response needs
+/// are downloaded before anything else and the response blocks until they are verified. Files not
+/// selected for download are not found.
+pub async fn serve(
+    listener: TcpListener,
+    info_hash: [u8; 20],
+    paths: Vec<String>,
+    download: Arc<Mutex<Download>>,
+) -> anyhow::Result<()> {
+    let served = Arc::new(Served {
+        info_hash: hex::encode(info_hash),
+        paths,
+        download,
+    });
+    loop {
+        let (stream, addr) = listener.accept().await.context("accept HTTP connection")?;
+        let served = Arc::clone(&served);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &served).await {
+                eprintln!("HTTP client {addr}: {e:#}");
+            }
+        });
+    }
+}
+
+/// URLs of the files of a torrent served at `addr`
+pub fn urls(addr: std::net::SocketAddr, info_hash: &[u8; 20], paths: &[String]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| format!("http://{addr}/{}/{}", hex::encode(info_hash), percent_encode(path)))
+        .collect()
+}
+
+/// Answer a single request, the connection being closed afterwards
+async fn handle(stream: TcpStream, served: &Served) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await.context("read request line")?;
+    let mut range = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await.context("read header")? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return respond(&mut writer, "400 Bad Request", &[]).await;
+    };
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut writer, "405 Method Not Allowed", &[("Allow", "GET, HEAD".into())]).await;
+    }
+    let file = target
+        .strip_prefix('/')
+        .and_then(|target| target.split_once('/'))
+        .filter(|(info_hash, _)| info_hash.eq_ignore_ascii_case(&served.info_hash))
+        .and_then(|(_, path)| percent_decode(path))
+        .and_then(|path| served.paths.iter().position(|p| *p == path));
+    let Some(file) = file else {
+        return respond(&mut writer, "404 Not Found", &[]).await;
+    };
+
+    let storage = Arc::clone(served.download.lock().expect("download lock poisoned").storage());
+    let entry = &storage.files()[file];
+    let (status, start, end) = match range.map(|range| parse_range(&range, entry.length)) {
+        None => ("200 OK", 0, entry.length),
+        Some(Some((start, end))) => ("206 Partial Content", start, end),
+        Some(None) => {
+            let content_range = format!("bytes */{}", entry.length);
+            return respond(&mut writer, "416 Range Not Satisfiable", &[("Content-Range", content_range)]).await;
+        }
+    };
+    // Files, or parts of them, which are not downloaded could never be sent
+    let piece_length = storage.piece_length();
+    let mut pieces = (entry.offset + start) / piece_length..(entry.offset + end).div_ceil(piece_length);
+    if !pieces.all(|piece| served.download.lock().expect("download lock poisoned").planned(piece)) {
+        return respond(&mut writer, "404 Not Found", &[]).await;
+    }
+    let mut headers = vec![
+        ("Content-Type", content_type(&served.paths[file]).to_string()),
+        ("Content-Length", (end - start).to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+    ];
+    if status.starts_with("206") {
+        headers.push(("Content-Range", format!("bytes {start}-{}/{}", end - 1, entry.length)));
+    }
+    respond(&mut writer, status, &headers).await?;
+    if method == "HEAD" {
+        return Ok(());
+    }
+
+    // Stream the range piece by piece, in the torrent's global offsets
+    let (mut pos, end) = (entry.offset + start, entry.offset + end);
+    while pos < end {
+        let piece = pos / piece_length;
+        served
+            .download
+            .lock()
+            .expect("download lock poisoned")
+            .prioritize(piece..piece + STREAM_READAHEAD);
+        download::wait_for_piece(&served.download, piece).await?;
+
+        let chunk_end = usize::min(end, (piece + 1) * piece_length);
+        let data = storage
+            .read_block(piece, pos - piece * piece_length, chunk_end - pos)
+            .await?;
+        writer.write_all(&data).await.context("send file data")?;
+        pos = chunk_end;
+    }
+    writer.shutdown().await.context("close HTTP connection")
+}
+
+async fn respond(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    status: &str,
+    headers: &[(&str, String)],
+) -> anyhow::Result<()> {
+    let mut response = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !headers.iter().any(|(name, _)| *name == "Content-Length") {
+        response.push_str("Content-Length: 0\r\n");
+    }
+    response.push_str("\r\n");
+    writer.write_all(response.as_bytes()).await.context("send HTTP response")
+}
+
+/// Parse a single `bytes=` range into a `[start, end)` interval, `None` if it is not satisfiable.
+/// Positions past the end of the file are clamped to it.
+fn parse_range(range: &str, length: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.strip_prefix("bytes=")?.trim().split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (length.saturating_sub(position(suffix)?), length),
+        (start, "") => (position(start)?, length),
+        (start, end) => (position(start)?, usize::min(position(end)?.saturating_add(1), length)),
+    };
+    (start < end).then_some((start, end))
+}
+
+/// Parse a byte position, saturating positions too large for `usize`
+fn position(digits: &str) -> Option<usize> {
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some(digits.parse().unwrap_or(usize::MAX))
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase()).as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_encode(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut input = path.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitfield::Bitfield;
+    use crate::resume::{Progress, TrackerStats};
+    use crate::storage::Storage;
+    use crate::testing;
+    use tokio::io::AsyncReadExt;
+
+    /// A download of file `a` of a torrent of two 20 byte files in 16 byte pieces, the second piece
+    /// straddling both, with the first two pieces on disk
+    async fn served(root: &std::path::Path) -> (Arc<Mutex<Download>>, Vec<u8>) {
+        let (torrent, data) = testing::torrent(&[20, 20], 16);
+        let storage = Arc::new(Storage::new(&torrent.info, root).unwrap());
+        storage.write_block(0, 0, &data[..16]).await.unwrap();
+        storage.write_block(1, 0, &data[16..32]).await.unwrap();
+        let mut download = Download::new(&torrent, storage, [0, 1]);
+        let mut have = Bitfield::new(3);
+        have.set(0).unwrap();
+        have.set(1).unwrap();
+        download.restore(&Progress { have, partial: Vec::new(), stats: TrackerStats::default() });
+        (Arc::new(Mutex::new(download)), data)
+    }
+
+    /// Send a request to the server at `addr`, returning the status line and the body
+    async fn get(addr: std::net::SocketAddr, path: &str, range: Option<&str>) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let range = range.map_or(String::new(), |range| format!("Range: {range}\r\n"));
+        let request = format!("GET /{}/{path} HTTP/1.1\r\n{range}\r\n", hex::encode([7; 20]));
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[..head_end]).lines().next().unwrap().to_string();
+        (status, response[head_end + 4..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn files_not_downloaded_are_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let (download, data) = served(dir.path()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, [7; 20], vec!["a".to_string(), "b".to_string()], download));
+
+        assert_eq!(get(addr, "a", None).await, ("HTTP/1.1 200 OK".to_string(), data[..20].to_vec()));
+        assert_eq!(get(addr, "b", None).await.0, "HTTP/1.1 404 Not Found");
+        // The start of `b` is in the piece straddling both files
+        let (status, body) = get(addr, "b", Some("bytes=0-9")).await;
+        assert_eq!((status.as_str(), body), ("HTTP/1.1 206 Partial Content", data[20..30].to_vec()));
+        assert_eq!(get(addr, "b", Some("bytes=10-")).await.0, "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn readers_fail_once_the_download_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, _) = testing::torrent(&[20, 20], 16);
+        let storage = Arc::new(Storage::new(&torrent.info, dir.path()).unwrap());
+        let download = Arc::new(Mutex::new(Download::new(&torrent, storage, [0, 1, 2])));
+        let reader = tokio::spawn({
+            let download = Arc::clone(&download);
+            async move { download::wait_for_piece(&download, 2).await }
+        });
+        // Without any peer, the download stops right away
+        let bandwidth = crate::ratelimit::Bandwidth::new(Default::default()).torrent();
+        assert!(download::run(Arc::clone(&download), &torrent, &[], None, 0, bandwidth, None).await.is_err());
+        assert!(reader.await.unwrap().is_err());
+        assert!(download::wait_for_piece(&download, 1).await.is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 1000)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=5-4", 1000), None);
+        assert_eq!(parse_range("bytes=+5-9", 1000), None);
+        assert_eq!(parse_range("items=0-9", 1000), None);
+    }
+
+    #[test]
+    fn huge_positions_are_clamped() {
+        assert_eq!(parse_range("bytes=0-18446744073709551615", 1000), Some((0, 1000)));
+        assert_eq!(parse_range("bytes=0-99999999999999999999999", 1000), Some((0, 1000)));
+        assert_eq!(parse_range("bytes=-99999999999999999999999", 1000), Some((0, 1000)));
+        assert_eq!(parse_range("bytes=99999999999999999999999-", 1000), None);
+    }
+}
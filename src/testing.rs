@@ -0,0 +1,31 @@
+//! Helpers shared by the tests of several modules
+
+use crate::hash::Hashes;
+use crate::{File, Info, Keys, Torrent};
+use sha1::{Digest, Sha1};
+
+/// Number of random inputs per property test, far fewer under Miri which is much slower
+pub const CASES: usize = if cfg!(miri) { 64 } else { 10_000 };
+
+/// A torrent named `t` of files `a`, `b`, ... of the given lengths, with the content it hashes:
+/// every byte is its offset in the torrent, wrapping at 256
+pub fn torrent(lengths: &[usize], piece_length: usize) -> (Torrent, Vec<u8>) {
+    let data: Vec<u8> = (0..lengths.iter().sum()).map(|i: usize| i as u8).collect();
+    let files = lengths
+        .iter()
+        .enumerate()
+        .map(|(i, &length)| File { length, path: vec![char::from(b'a' + i as u8).to_string()] })
+        .collect();
+    let torrent = Torrent {
+        announce: String::new(),
+        nodes: Vec::new(),
+        info: Info {
+            name: "t".to_string(),
+            piece_length,
+            pieces: Hashes(data.chunks(piece_length).map(|piece| Sha1::digest(piece).into()).collect()),
+            private: None,
+            keys: Keys::MultiFile { files },
+        },
+    };
+    (torrent, data)
+}